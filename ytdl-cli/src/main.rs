@@ -0,0 +1,394 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{err_msg, format_err, Error};
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+use std::time::Duration;
+use url::form_urlencoded;
+use ytdl_lib::client::{ClientConfig, CookieJar};
+use ytdl_lib::clip::{make_gif, Timestamp};
+use ytdl_lib::{mime_extension, source_url, Video, VideoInfo};
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let id = Arg::with_name("ID")
+        .help("the video id, or a youtube watch url")
+        .required(true);
+    let output = Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .help("where to write the file, use - for stdout");
+    let itag = Arg::with_name("itag")
+        .short("f")
+        .long("itag")
+        .takes_value(true)
+        .help("the itag of the format to use, defaults to the best muxed one");
+    App::new("ytdl")
+        .about("Download videos, audio, captions and gifs from youtube")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("print the results as json, useful for scripting"),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .takes_value(true)
+                .global(true)
+                .help("send the requests through an http or socks5 proxy"),
+        )
+        .arg(
+            Arg::with_name("user-agent")
+                .long("user-agent")
+                .takes_value(true)
+                .global(true)
+                .help("the User-Agent header to send"),
+        )
+        .arg(
+            Arg::with_name("cookies")
+                .long("cookies")
+                .takes_value(true)
+                .global(true)
+                .help("a netscape cookies.txt file to send cookies from"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .global(true)
+                .help("the timeout of every request in seconds"),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .takes_value(true)
+                .global(true)
+                .help("how many times a failed request is retried, defaults to 2"),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("show the video details")
+                .arg(id.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("formats")
+                .about("list the available formats")
+                .arg(id.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("download")
+                .about("download the video")
+                .arg(id.clone())
+                .arg(itag.clone())
+                .arg(output.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("audio")
+                .about("download the best audio only format")
+                .arg(id.clone())
+                .arg(output.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("captions")
+                .about("list the captions, or download one with --lang")
+                .arg(id.clone())
+                .arg(
+                    Arg::with_name("lang")
+                        .short("l")
+                        .long("lang")
+                        .takes_value(true)
+                        .help("the language code of the captions to download"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .help("the captions format, e.g vtt or srv3"),
+                )
+                .arg(output.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("gif")
+                .about("extract a gif from the video")
+                .arg(id)
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the start time, like 1:02.5, 90s or 01:02:03"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the end time, like 1:12.5, 100s or 01:02:13"),
+                )
+                .arg(itag)
+                .arg(output),
+        )
+}
+
+fn main() {
+    env_logger::init();
+    let matches = app().get_matches();
+    let json = matches.is_present("json");
+    if let Err(error) = run(&matches) {
+        if json {
+            println!("{}", json!({ "error": format!("{}", error.as_fail()) }));
+        } else {
+            eprintln!("Error: {}", error.as_fail());
+        }
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("info", Some(args)) => info(args),
+        ("formats", Some(args)) => formats(args),
+        ("download", Some(args)) => download(args),
+        ("audio", Some(args)) => audio(args),
+        ("captions", Some(args)) => captions(args),
+        ("gif", Some(args)) => gif(args),
+        _ => Err(err_msg("Unknown command, try --help")),
+    }
+}
+
+fn info(args: &ArgMatches) -> Result<()> {
+    let video = load_video(args)?;
+    let sources = video.video_sources().map_or(0, Vec::len);
+    let adaptive = video.adaptive_sources().map_or(0, Vec::len);
+    if args.is_present("json") {
+        print_json(&json!({
+            "id": video.id(),
+            "title": video.title(),
+            "author": video.author(),
+            "length_seconds": video.length_seconds(),
+            "formats": sources + adaptive,
+            "captions": video.caption_tracks().len(),
+        }))
+    } else {
+        println!("id:       {}", video.id());
+        println!("title:    {}", video.title().unwrap_or("-"));
+        println!("author:   {}", video.author().unwrap_or("-"));
+        match video.length_seconds() {
+            Some(length) => println!("length:   {}s", length),
+            None => println!("length:   -"),
+        }
+        println!("formats:  {}", sources + adaptive);
+        println!("captions: {}", video.caption_tracks().len());
+        Ok(())
+    }
+}
+
+fn formats(args: &ArgMatches) -> Result<()> {
+    let video = load_video(args)?;
+    let sources: Vec<&VideoInfo> = video
+        .video_sources()
+        .into_iter()
+        .chain(video.adaptive_sources())
+        .flatten()
+        .collect();
+    if args.is_present("json") {
+        return print_json(&sources);
+    }
+    println!("{:<6} {:<12} {:<45}", "itag", "quality", "type");
+    for source in sources {
+        let field = |key: &str| source.get(key).map_or("-", String::as_str);
+        let quality = source
+            .get("quality_label")
+            .or_else(|| source.get("quality"))
+            .map_or("-", String::as_str);
+        println!("{:<6} {:<12} {:<45}", field("itag"), quality, field("type"));
+    }
+    Ok(())
+}
+
+fn download(args: &ArgMatches) -> Result<()> {
+    let video = load_video(args)?;
+    let itag = match args.value_of("itag") {
+        Some(itag) => itag.to_string(),
+        None => default_itag(&video)?,
+    };
+    save_source(args, &video, &itag)
+}
+
+fn audio(args: &ArgMatches) -> Result<()> {
+    let video = load_video(args)?;
+    let itag = video
+        .best_audio()
+        .and_then(|source| source.get("itag"))
+        .ok_or_else(|| err_msg("This video has no audio only format"))?
+        .to_string();
+    save_source(args, &video, &itag)
+}
+
+fn captions(args: &ArgMatches) -> Result<()> {
+    let video = load_video(args)?;
+    let tracks = video.caption_tracks();
+    let lang = match args.value_of("lang") {
+        Some(lang) => lang,
+        None if args.is_present("json") => return print_json(&tracks),
+        None => {
+            if tracks.is_empty() {
+                println!("This video has no captions");
+            }
+            for track in tracks {
+                let kind = if track.kind.is_empty() { "" } else { " (auto)" };
+                println!("{:<8} {}{}", track.language_code, track.name.simple_text, kind);
+            }
+            return Ok(());
+        }
+    };
+    let track = tracks
+        .iter()
+        .find(|track| track.language_code == lang)
+        .ok_or_else(|| format_err!("There are no captions in '{}'", lang))?;
+    let format = args.value_of("format");
+    let body = video.download_caption(track, format)?;
+    let default_name = format!(
+        "{}.{}.{}",
+        file_stem(&video),
+        lang,
+        format.unwrap_or("xml")
+    );
+    let path = write_output(args, &default_name, body.as_bytes())?;
+    report_saved(args, &path, body.len() as u64)
+}
+
+fn gif(args: &ArgMatches) -> Result<()> {
+    let start = Timestamp::from(args.value_of("start").unwrap_or_default())?;
+    let end = Timestamp::from(args.value_of("end").unwrap_or_default())?;
+    let duration = start.until(end)?;
+    let video = load_video(args)?;
+    let itag = match args.value_of("itag") {
+        Some(itag) => itag.to_string(),
+        None => default_itag(&video)?,
+    };
+    let url = video
+        .find_source(&itag)
+        .and_then(source_url)
+        .ok_or_else(|| format_err!("There is no source with itag {}", itag))?;
+    let body = make_gif(&url, &start, &duration)?;
+    let default_name = format!("{}.gif", file_stem(&video));
+    let path = write_output(args, &default_name, &body)?;
+    report_saved(args, &path, body.len() as u64)
+}
+
+fn load_video(args: &ArgMatches) -> Result<Video> {
+    let id = video_id(args.value_of("ID").unwrap_or_default());
+    let mut video = Video::with_config(&id, &client_config(args)?)?;
+    video.initialize()?;
+    Ok(video)
+}
+
+fn client_config(args: &ArgMatches) -> Result<ClientConfig> {
+    let mut config = ClientConfig {
+        proxy: args.value_of("proxy").map(str::to_string),
+        user_agent: args.value_of("user-agent").map(str::to_string),
+        ..ClientConfig::default()
+    };
+    if let Some(path) = args.value_of("cookies") {
+        config.cookies = CookieJar::from_file(path)?;
+    }
+    if let Some(timeout) = args.value_of("timeout") {
+        let seconds = timeout
+            .parse()
+            .map_err(|_| format_err!("Bad timeout '{}', expected seconds", timeout))?;
+        config.timeout = Some(Duration::from_secs(seconds));
+    }
+    if let Some(retries) = args.value_of("retries") {
+        let retries: u32 = retries
+            .parse()
+            .map_err(|_| format_err!("Bad retries count '{}'", retries))?;
+        config.retry.max_attempts = retries.saturating_add(1);
+    }
+    Ok(config)
+}
+
+// Accept a bare id, a watch url or a youtu.be short link.
+fn video_id(input: &str) -> String {
+    if let Some(pos) = input.find("youtu.be/") {
+        let rest = &input[pos + "youtu.be/".len()..];
+        return rest.split(&['?', '&'][..]).next().unwrap_or(rest).to_string();
+    }
+    if let Some(pos) = input.find('?') {
+        let query = &input[pos + 1..];
+        if let Some((_, id)) = form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == "v") {
+            return id.into_owned();
+        }
+    }
+    input.to_string()
+}
+
+fn default_itag(video: &Video) -> Result<String> {
+    video
+        .video_sources()
+        .and_then(|sources| sources.first())
+        .and_then(|source| source.get("itag"))
+        .cloned()
+        .ok_or_else(|| err_msg("This video has no downloadable formats"))
+}
+
+fn save_source(args: &ArgMatches, video: &Video, itag: &str) -> Result<()> {
+    let extension = video
+        .find_source(itag)
+        .and_then(|source| source.get("type"))
+        .map_or("mp4", |t| mime_extension(t));
+    let default_name = format!("{}.{}", file_stem(video), extension);
+    let path = args.value_of("output").unwrap_or(&default_name).to_string();
+    let written = if path == "-" {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        video.download(itag, &mut handle)?
+    } else {
+        let mut file = File::create(&path)?;
+        video.download(itag, &mut file)?
+    };
+    report_saved(args, &path, written)
+}
+
+fn file_stem(video: &Video) -> String {
+    let title = video.title().unwrap_or_else(|| video.id());
+    title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn write_output(args: &ArgMatches, default_name: &str, body: &[u8]) -> Result<String> {
+    let path = args.value_of("output").unwrap_or(default_name);
+    if path == "-" {
+        io::stdout().write_all(body)?;
+    } else {
+        File::create(path)?.write_all(body)?;
+    }
+    Ok(path.to_string())
+}
+
+fn report_saved(args: &ArgMatches, path: &str, bytes: u64) -> Result<()> {
+    if path == "-" {
+        return Ok(());
+    }
+    if args.is_present("json") {
+        print_json(&json!({ "file": path, "bytes": bytes }))
+    } else {
+        eprintln!("Saved {} bytes to {}", bytes, path);
+        Ok(())
+    }
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
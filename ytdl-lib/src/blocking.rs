@@ -0,0 +1,61 @@
+use crate::Result;
+use failure::{err_msg, Error};
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::sync::Mutex;
+use tokio::runtime::{Runtime, TaskExecutor};
+
+// how many chunks may wait for the writer before the download is paused.
+const CHUNKS_BUFFER: usize = 16;
+
+lazy_static! {
+    // the blocking API runs its futures here, so it works from any thread,
+    // even from inside another executor.
+    static ref RUNTIME: Mutex<Runtime> =
+        Mutex::new(Runtime::new().expect("Cannot start the background runtime"));
+}
+
+fn executor() -> TaskExecutor {
+    RUNTIME.lock().unwrap().executor()
+}
+
+/// Run `future` on the background runtime and wait for its result.
+pub(crate) fn block_on<F>(future: F) -> Result<F::Item>
+where
+    F: Future<Error = Error> + Send + 'static,
+    F::Item: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    executor().spawn(future.then(move |result| {
+        let _ = tx.send(result);
+        Ok(())
+    }));
+    rx.wait()
+        .map_err(|_| err_msg("The background runtime dropped the future"))?
+}
+
+/// Run `stream` on the background runtime and write its chunks into `writer`,
+/// returns the number of bytes written.
+pub(crate) fn copy_stream<S, W>(stream: S, writer: &mut W) -> Result<u64>
+where
+    S: Stream<Error = Error> + Send + 'static,
+    S::Item: AsRef<[u8]> + Send + 'static,
+    W: Write,
+{
+    let (tx, rx) = mpsc::channel(CHUNKS_BUFFER);
+    let forward = tx
+        .send_all(stream.then(Ok::<_, mpsc::SendError<_>>))
+        .map(|_| ())
+        .map_err(|_| ());
+    executor().spawn(forward);
+    let mut written = 0;
+    for chunk in rx.wait() {
+        let chunk = chunk.map_err(|_| err_msg("The background runtime dropped the stream"))??;
+        writer.write_all(chunk.as_ref())?;
+        written += chunk.as_ref().len() as u64;
+    }
+    writer.flush()?;
+    Ok(written)
+}
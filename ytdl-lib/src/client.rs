@@ -0,0 +1,516 @@
+use crate::blocking;
+use crate::error::FetchError;
+use crate::{BoxFuture, BoxStream, Result};
+use failure::format_err;
+use futures::future::{self, Loop};
+use futures::{Future, Stream};
+use log::{debug, info, warn};
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, COOKIE, RANGE, RETRY_AFTER, USER_AGENT,
+};
+use reqwest::r#async::{Chunk, Client, Response};
+use reqwest::{Proxy, StatusCode};
+use std::cmp;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::timer::Delay;
+use url::Url;
+
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// How the requests to youtube should be made.
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    /// An `http://`, `https://`, `socks5://` or `socks5h://` proxy url.
+    pub proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub accept_language: Option<String>,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// The timeout of the whole request, reqwest defaults to 30 seconds.
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub cookies: CookieJar,
+    pub retry: RetryPolicy,
+}
+
+/// How failed requests are retried, with an exponential backoff and jitter between attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The total number of attempts, 1 disables retrying.
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled on every attempt.
+    pub base_delay: Duration,
+    /// The longest we wait between two attempts, when a `Retry-After` asks for more we give up.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// How long to wait before the next attempt, `None` when we should give up.
+    fn next_delay(&self, attempts: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            _ if attempts >= self.max_attempts => None,
+            Some(delay) if delay > self.max_delay => {
+                warn!("Server asked to retry after {:?}, giving up", delay);
+                None
+            }
+            Some(delay) => Some(delay),
+            None => Some(self.backoff(attempts)),
+        }
+    }
+
+    /// The delay after the given failed attempt, somewhere in the upper half of the
+    /// exponential delay so concurrent clients don't retry all at once.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.base_delay * 2u32.pow(cmp::min(attempt - 1, 16));
+        let delay = cmp::min(exponential, self.max_delay);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+    }
+}
+
+/// The http client shared by every request of a video, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: Client,
+    cookies: CookieJar,
+    retry: RetryPolicy,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient {
+            inner: Arc::new(Inner {
+                client: Client::new(),
+                cookies: CookieJar::default(),
+                retry: RetryPolicy::default(),
+            }),
+        }
+    }
+}
+
+impl HttpClient {
+    pub fn new(config: &ClientConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Some(user_agent) = &config.user_agent {
+            headers.insert(USER_AGENT, HeaderValue::from_str(user_agent)?);
+        }
+        if let Some(language) = &config.accept_language {
+            headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_str(language)?);
+        }
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let mut builder = Client::builder().default_headers(headers);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        Ok(HttpClient {
+            inner: Arc::new(Inner {
+                client: builder.build()?,
+                cookies: config.cookies.clone(),
+                retry: config.retry.clone(),
+            }),
+        })
+    }
+
+    /// Send a GET request with the cookies that match the url, retrying it on
+    /// network errors and on the statuses that are worth a retry.
+    pub fn get_async(&self, url: &str) -> BoxFuture<Response> {
+        self.get_range_async(url, None)
+    }
+
+    /// Like `get_async`, asking only for the given `Range` (like `bytes=100-`),
+    /// the response is then a 206 with a `Content-Range`.
+    pub fn get_range_async(&self, url: &str, range: Option<&str>) -> BoxFuture<Response> {
+        let cookie = match Url::parse(url) {
+            Ok(parsed) => self.inner.cookies.header_for(&parsed),
+            Err(error) => return Box::new(future::err(error.into())),
+        };
+        let inner = self.inner.clone();
+        let url = url.to_string();
+        let range = range.map(str::to_string);
+        let attempts = future::loop_fn(1, move |attempts| {
+            let mut request = inner.client.get(&url);
+            if let Some(cookie) = &cookie {
+                request = request.header(COOKIE, cookie.as_str());
+            }
+            if let Some(range) = &range {
+                request = request.header(RANGE, range.as_str());
+            }
+            let inner = inner.clone();
+            let url = url.clone();
+            request
+                .send()
+                .then(move |result| -> BoxFuture<Loop<Response, u32>> {
+                    let (reason, retry_after) = match result {
+                        Ok(res) if res.status().is_success() => {
+                            if attempts > 1 {
+                                info!("Request to {} succeeded after {} attempts", url, attempts);
+                            }
+                            return Box::new(future::ok(Loop::Break(res)));
+                        }
+                        Ok(res) if is_retryable(res.status()) => {
+                            (format!("status {}", res.status()), retry_after(&res))
+                        }
+                        Ok(res) => {
+                            let error = FetchError::Status {
+                                url,
+                                status: res.status().as_u16(),
+                                attempts,
+                            };
+                            return Box::new(future::err(error.into()));
+                        }
+                        Err(error) if error.is_redirect() || error.is_serialization() => {
+                            return Box::new(future::err(error.into()));
+                        }
+                        Err(error) => (error.to_string(), None),
+                    };
+                    match inner.retry.next_delay(attempts, retry_after) {
+                        Some(delay) => {
+                            warn!(
+                                "Request to {} failed ({}), retry {}/{} in {:?}",
+                                url,
+                                reason,
+                                attempts,
+                                inner.retry.max_attempts - 1,
+                                delay
+                            );
+                            let retry = Delay::new(Instant::now() + delay)
+                                .map(move |_| Loop::Continue(attempts + 1))
+                                .from_err();
+                            Box::new(retry)
+                        }
+                        None => {
+                            let error = FetchError::RetriesExhausted {
+                                url,
+                                attempts,
+                                reason,
+                            };
+                            Box::new(future::err(error.into()))
+                        }
+                    }
+                })
+        });
+        Box::new(attempts)
+    }
+
+    /// Get the whole body of a request.
+    pub fn get_bytes_async(&self, url: &str) -> BoxFuture<Vec<u8>> {
+        let body = self
+            .get_async(url)
+            .and_then(|res| res.into_body().concat2().from_err())
+            .map(|body| body.to_vec());
+        Box::new(body)
+    }
+
+    pub fn get_string_async(&self, url: &str) -> BoxFuture<String> {
+        let body = self
+            .get_bytes_async(url)
+            .and_then(|body| Ok(String::from_utf8(body)?));
+        Box::new(body)
+    }
+
+    /// Stream the body of a request as it arrives.
+    pub fn get_stream(&self, url: &str) -> BoxStream<Chunk> {
+        let body = self
+            .get_async(url)
+            .map(|res| res.into_body().from_err())
+            .flatten_stream();
+        Box::new(body)
+    }
+
+    pub fn get_bytes(&self, url: &str) -> Result<Vec<u8>> {
+        blocking::block_on(self.get_bytes_async(url))
+    }
+
+    pub fn get_string(&self, url: &str) -> Result<String> {
+        blocking::block_on(self.get_string_async(url))
+    }
+
+    /// Copy the body of a request into `writer`, returns the number of bytes written.
+    pub fn copy_to<W: Write>(&self, url: &str, writer: &mut W) -> Result<u64> {
+        blocking::copy_stream(self.get_stream(url), writer)
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// Read the `Retry-After` header, in seconds or as an http date.
+fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub domain: String,
+    pub include_subdomains: bool,
+    pub path: String,
+    pub secure: bool,
+    /// Unix timestamp, zero for session cookies.
+    pub expires: u64,
+    pub name: String,
+    pub value: String,
+}
+
+/// Cookies loaded from a Netscape `cookies.txt` file, as exported by browsers and curl.
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| format_err!("Cannot read cookies file {}: {}", path.display(), e))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut cookies = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            let line = line.strip_prefix(HTTP_ONLY_PREFIX).unwrap_or(line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                return Err(format_err!(
+                    "Bad cookies.txt line {}, expected 7 tab separated fields",
+                    number + 1
+                ));
+            }
+            let expires = fields[4].parse().map_err(|_| {
+                format_err!(
+                    "Bad cookies.txt line {}, the expiry {:?} is not a unix timestamp",
+                    number + 1,
+                    fields[4]
+                )
+            })?;
+            cookies.push(Cookie {
+                domain: fields[0].trim_start_matches('.').to_lowercase(),
+                include_subdomains: fields[1].eq_ignore_ascii_case("TRUE"),
+                path: fields[2].to_string(),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                expires,
+                name: fields[5].to_string(),
+                value: fields[6].to_string(),
+            });
+        }
+        debug!("Loaded {} cookies", cookies.len());
+        Ok(CookieJar { cookies })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    /// Build the `Cookie` header value for a request to `url`.
+    pub fn header_for(&self, url: &Url) -> Option<String> {
+        let host = url.host_str()?.to_lowercase();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let pairs: Vec<String> = self
+            .cookies
+            .iter()
+            .filter(|c| {
+                c.domain == host
+                    || (c.include_subdomains && host.ends_with(&format!(".{}", c.domain)))
+            })
+            .filter(|c| path_matches(url.path(), &c.path))
+            .filter(|c| !c.secure || url.scheme() == "https")
+            .filter(|c| c.expires == 0 || c.expires > now)
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+}
+
+// RFC 6265 5.1.4, `/api` covers `/api` and `/api/v1` but not `/apifoo`.
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    match request_path.strip_prefix(cookie_path) {
+        Some(rest) => cookie_path.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // far in the future, and long gone.
+    const LATER: &str = "4102444800";
+    const EXPIRED: &str = "946684800";
+
+    fn jar(lines: &[&str]) -> CookieJar {
+        CookieJar::parse(&lines.join("\n")).unwrap()
+    }
+
+    fn header(jar: &CookieJar, url: &str) -> Option<String> {
+        jar.header_for(&Url::parse(url).unwrap())
+    }
+
+    fn line(
+        domain: &str,
+        subdomains: &str,
+        path: &str,
+        secure: &str,
+        expires: &str,
+        name: &str,
+    ) -> String {
+        [domain, subdomains, path, secure, expires, name, "v"].join("\t")
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let cookie = line(".youtube.com", "TRUE", "/", "FALSE", LATER, "PREF");
+        let jar = jar(&[
+            "# Netscape HTTP Cookie File",
+            "",
+            "   ",
+            &cookie,
+            "# the end",
+        ]);
+        assert_eq!(jar.cookies.len(), 1);
+        assert_eq!(jar.cookies[0].domain, "youtube.com");
+        assert_eq!(jar.cookies[0].name, "PREF");
+    }
+
+    #[test]
+    fn http_only_lines_are_cookies() {
+        let cookie = format!(
+            "#HttpOnly_{}",
+            line(".youtube.com", "TRUE", "/", "TRUE", LATER, "SID")
+        );
+        let jar = jar(&[&cookie]);
+        assert_eq!(jar.cookies.len(), 1);
+        assert_eq!(jar.cookies[0].domain, "youtube.com");
+        assert!(jar.cookies[0].secure);
+    }
+
+    #[test]
+    fn windows_line_endings() {
+        let cookie = format!("{}\r", line("youtube.com", "FALSE", "/", "FALSE", "0", "A"));
+        let jar = jar(&[&cookie]);
+        assert_eq!(jar.cookies[0].value, "v");
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        assert!(CookieJar::parse("youtube.com\tTRUE\t/").is_err());
+        let bad_expiry = line("youtube.com", "FALSE", "/", "FALSE", "soon", "A");
+        assert!(CookieJar::parse(&bad_expiry).is_err());
+    }
+
+    #[test]
+    fn expired_cookies_are_not_sent() {
+        let jar = jar(&[
+            &line("youtube.com", "FALSE", "/", "FALSE", EXPIRED, "OLD"),
+            &line("youtube.com", "FALSE", "/", "FALSE", "0", "SESSION"),
+            &line("youtube.com", "FALSE", "/", "FALSE", LATER, "NEW"),
+        ]);
+        assert_eq!(
+            header(&jar, "https://youtube.com/watch").as_deref(),
+            Some("SESSION=v; NEW=v")
+        );
+    }
+
+    #[test]
+    fn domain_matching() {
+        let jar = jar(&[
+            &line(".youtube.com", "TRUE", "/", "FALSE", "0", "ALL"),
+            &line("youtube.com", "FALSE", "/", "FALSE", "0", "EXACT"),
+        ]);
+        assert_eq!(
+            header(&jar, "https://YouTube.com/").as_deref(),
+            Some("ALL=v; EXACT=v")
+        );
+        assert_eq!(
+            header(&jar, "https://www.youtube.com/").as_deref(),
+            Some("ALL=v")
+        );
+        assert_eq!(header(&jar, "https://notyoutube.com/"), None);
+        assert_eq!(header(&jar, "https://youtube.com.evil.com/"), None);
+    }
+
+    #[test]
+    fn path_and_secure_matching() {
+        let jar = jar(&[
+            &line("youtube.com", "FALSE", "/api", "FALSE", "0", "API"),
+            &line("youtube.com", "FALSE", "/", "TRUE", "0", "SECURE"),
+        ]);
+        assert_eq!(header(&jar, "http://youtube.com/"), None);
+        assert_eq!(
+            header(&jar, "https://youtube.com/api/v1").as_deref(),
+            Some("API=v; SECURE=v")
+        );
+        assert_eq!(
+            header(&jar, "https://youtube.com/api").as_deref(),
+            Some("API=v; SECURE=v")
+        );
+        assert_eq!(
+            header(&jar, "https://youtube.com/apifoo").as_deref(),
+            Some("SECURE=v")
+        );
+    }
+}
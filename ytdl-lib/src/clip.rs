@@ -0,0 +1,741 @@
+use crate::error::InputError;
+use crate::ffmpeg::{self, Cancel, Limits};
+use crate::Result;
+use failure::err_msg;
+use lazy_static::lazy_static;
+use regex::{Captures, Match, Regex};
+use std::fmt;
+use std::io::Write;
+use std::ops::Add;
+use std::process::Command;
+use std::sync::RwLock;
+
+const CLOCK_REGEX_STR: &str = r"^(?:(?:(\d+):)?(\d+):)?(\d+)(?:\.(\d+))?$";
+const UNITS_REGEX_STR: &str = r"^(?:(\d+)h)?(?:(\d+)m)?(?:(\d+)(?:\.(\d+))?s)?$";
+const ISO_8601_REGEX_STR: &str =
+    r"(?i)^P(?:(\d+)D)?(?:T(?:(\d+)H)?(?:(\d+)M)?(?:(\d+)(?:[.,](\d+))?S)?)?$";
+
+lazy_static! {
+    static ref CLOCK_REGEX: Regex = Regex::new(CLOCK_REGEX_STR).unwrap();
+    static ref UNITS_REGEX: Regex = Regex::new(UNITS_REGEX_STR).unwrap();
+    static ref ISO_8601_REGEX: Regex = Regex::new(ISO_8601_REGEX_STR).unwrap();
+    static ref FFMPEG_PATH: RwLock<String> = RwLock::new("ffmpeg".to_string());
+}
+
+/// Run the ffmpeg binary at `path` instead of the one in the `PATH`.
+pub fn set_ffmpeg_path(path: &str) {
+    *FFMPEG_PATH.write().unwrap() = path.to_string();
+}
+
+fn ffmpeg(limits: &Limits) -> Command {
+    let mut command = Command::new(&*FFMPEG_PATH.read().unwrap());
+    command.args(ffmpeg::input_args(limits));
+    command
+}
+
+/// A time in a video, or a duration, in milliseconds. It reads as a clock
+/// (`1:02.5`, `01:02:03`), plain seconds (`90`, `62.5`), units (`90s`,
+/// `1m30s`, `1h2m`) or an ISO 8601 duration (`PT1M30.5S`).
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default, Hash)]
+pub struct Timestamp(u64);
+
+// saturates rather than wrap, use `until` for the time between two of them.
+impl Add for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, other: Timestamp) -> Timestamp {
+        Timestamp(self.0.saturating_add(other.0))
+    }
+}
+
+// as ffmpeg takes it, like `0:01:02.500`.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0 / 1000;
+        write!(
+            f,
+            "{}:{:02}:{:02}.{:03}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.0 % 1000
+        )
+    }
+}
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Self {
+        Timestamp(millis)
+    }
+
+    /// Saturates past `u64::MAX` milliseconds, some 584 million years.
+    pub fn from_secs(seconds: u64) -> Self {
+        Timestamp(seconds.saturating_mul(1000))
+    }
+
+    pub fn from(time: &str) -> Result<Timestamp> {
+        let time = time.trim();
+        let millis = CLOCK_REGEX
+            .captures(time)
+            .and_then(|captures| clock_millis(&captures))
+            .or_else(|| {
+                UNITS_REGEX
+                    .captures(time)
+                    .and_then(|captures| units_millis(&captures, false))
+            })
+            .or_else(|| {
+                ISO_8601_REGEX
+                    .captures(time)
+                    .and_then(|captures| units_millis(&captures, true))
+            });
+        match millis {
+            Some(millis) => Ok(Timestamp(millis)),
+            None => Err(InputError::Time {
+                reason: format!(
+                    "Wrong time '{}', it should be like 1:02.5, 01:02:03, 90, 90s, 1m30s or PT1M30S",
+                    time
+                ),
+            })?,
+        }
+    }
+
+    pub fn millis(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    /// The whole seconds, rounded up so a started second counts.
+    pub fn ceil_secs(self) -> u64 {
+        self.0.div_ceil(1000)
+    }
+
+    /// The duration from `self` to `end`, which must come after.
+    pub fn until(self, end: Timestamp) -> Result<Timestamp> {
+        if end <= self {
+            Err(InputError::Time {
+                reason: format!("The end ({}) should be after the start ({})", end, self),
+            })?
+        }
+        Ok(Timestamp(end.0 - self.0))
+    }
+}
+
+// `[[hours:]minutes:]seconds[.fraction]`, only the first field may overflow
+// into the next unit, like `90` or `90:00`.
+fn clock_millis(captures: &Captures) -> Option<u64> {
+    let field = |i| captures.get(i).map(|v| v.as_str().parse::<u64>());
+    let hours = field(1).transpose().ok()?;
+    let minutes = field(2).transpose().ok()?;
+    let seconds = field(3)?.ok()?;
+    if minutes.is_some() && seconds >= 60 || hours.is_some() && minutes.is_some_and(|m| m >= 60) {
+        return None;
+    }
+    join_millis(&[
+        (hours.unwrap_or(0), 3_600_000),
+        (minutes.unwrap_or(0), 60_000),
+        (seconds, 1000),
+        (fraction_millis(captures.get(4))?, 1),
+    ])
+}
+
+// The `h`, `m`, `s` units or the ISO 8601 ones, which start with the days.
+// At least one of them must be given.
+fn units_millis(captures: &Captures, with_days: bool) -> Option<u64> {
+    if captures.iter().skip(1).all(|group| group.is_none()) {
+        return None;
+    }
+    let field = |i| {
+        captures
+            .get(i)
+            .map_or(Ok(0), |v| v.as_str().parse::<u64>())
+            .ok()
+    };
+    let (days, hours) = if with_days { (field(1)?, 2) } else { (0, 1) };
+    join_millis(&[
+        (days, 86_400_000),
+        (field(hours)?, 3_600_000),
+        (field(hours + 1)?, 60_000),
+        (field(hours + 2)?, 1000),
+        (fraction_millis(captures.get(hours + 3))?, 1),
+    ])
+}
+
+fn join_millis(parts: &[(u64, u64)]) -> Option<u64> {
+    parts.iter().try_fold(0u64, |total, &(value, unit)| {
+        total.checked_add(value.checked_mul(unit)?)
+    })
+}
+
+// The digits after the point of the seconds, past the milliseconds are dropped.
+fn fraction_millis(fraction: Option<Match>) -> Option<u64> {
+    let digits = fraction.map_or("", |v| v.as_str());
+    format!("{:0<3}", &digits[..digits.len().min(3)])
+        .parse()
+        .ok()
+}
+
+/// The container and codec of an extracted clip.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ClipFormat {
+    #[default]
+    Gif,
+    /// Silent H.264, fragmented so it can be written to a pipe.
+    Mp4,
+    /// Silent VP9.
+    Webm,
+    /// Animated WebP.
+    Webp,
+    Apng,
+}
+
+impl ClipFormat {
+    pub fn from(format: &str) -> Result<ClipFormat> {
+        match format.to_lowercase().as_str() {
+            "gif" => Ok(ClipFormat::Gif),
+            "mp4" => Ok(ClipFormat::Mp4),
+            "webm" => Ok(ClipFormat::Webm),
+            "webp" => Ok(ClipFormat::Webp),
+            "apng" | "png" => Ok(ClipFormat::Apng),
+            other => Err(InputError::Unknown {
+                what: "format",
+                value: other.to_string(),
+                expected: "gif, mp4, webm, webp or apng",
+            })?,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ClipFormat::Gif => "image/gif",
+            ClipFormat::Mp4 => "video/mp4",
+            ClipFormat::Webm => "video/webm",
+            ClipFormat::Webp => "image/webp",
+            ClipFormat::Apng => "image/apng",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ClipFormat::Gif => "gif",
+            ClipFormat::Mp4 => "mp4",
+            ClipFormat::Webm => "webm",
+            ClipFormat::Webp => "webp",
+            ClipFormat::Apng => "png",
+        }
+    }
+
+    // The ffmpeg output options, `quality` goes from 1 (smallest) to 100 (best).
+    fn output_args(self, quality: u8) -> Vec<String> {
+        let quality = u32::from(quality.clamp(1, 100));
+        match self {
+            ClipFormat::Gif => vec!["-f".into(), "gif".into()],
+            ClipFormat::Mp4 => {
+                let crf = 40 - (quality - 1) * 22 / 99;
+                vec![
+                    "-c:v".into(),
+                    "libx264".into(),
+                    "-preset".into(),
+                    "superfast".into(),
+                    "-crf".into(),
+                    crf.to_string(),
+                    "-pix_fmt".into(),
+                    "yuv420p".into(),
+                    "-movflags".into(),
+                    "frag_keyframe+empty_moov".into(),
+                    "-f".into(),
+                    "mp4".into(),
+                ]
+            }
+            ClipFormat::Webm => {
+                let crf = 50 - (quality - 1) * 35 / 99;
+                vec![
+                    "-c:v".into(),
+                    "libvpx-vp9".into(),
+                    "-crf".into(),
+                    crf.to_string(),
+                    "-b:v".into(),
+                    "0".into(),
+                    "-deadline".into(),
+                    "realtime".into(),
+                    "-cpu-used".into(),
+                    "8".into(),
+                    "-f".into(),
+                    "webm".into(),
+                ]
+            }
+            ClipFormat::Webp => vec![
+                "-c:v".into(),
+                "libwebp".into(),
+                "-q:v".into(),
+                quality.to_string(),
+                "-loop".into(),
+                "0".into(),
+                "-f".into(),
+                "webp".into(),
+            ],
+            ClipFormat::Apng => vec!["-plays".into(), "0".into(), "-f".into(), "apng".into()],
+        }
+    }
+}
+
+/// How paletteuse maps the colors of a frame onto the palette.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Dither {
+    Bayer,
+    Heckbert,
+    FloydSteinberg,
+    Sierra2,
+    #[default]
+    Sierra2_4a,
+    None,
+}
+
+impl Dither {
+    pub fn from(dither: &str) -> Result<Dither> {
+        match dither.to_lowercase().as_str() {
+            "bayer" => Ok(Dither::Bayer),
+            "heckbert" => Ok(Dither::Heckbert),
+            "floyd_steinberg" => Ok(Dither::FloydSteinberg),
+            "sierra2" => Ok(Dither::Sierra2),
+            "sierra2_4a" => Ok(Dither::Sierra2_4a),
+            "none" => Ok(Dither::None),
+            other => Err(InputError::Unknown {
+                what: "dither",
+                value: other.to_string(),
+                expected: "bayer, heckbert, floyd_steinberg, sierra2, sierra2_4a or none",
+            })?,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Dither::Bayer => "bayer",
+            Dither::Heckbert => "heckbert",
+            Dither::FloydSteinberg => "floyd_steinberg",
+            Dither::Sierra2 => "sierra2",
+            Dither::Sierra2_4a => "sierra2_4a",
+            Dither::None => "none",
+        }
+    }
+}
+
+/// Which pixels palettegen counts when building the palette.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum StatsMode {
+    /// One palette for the whole clip.
+    #[default]
+    Full,
+    /// One palette favouring what moves between frames.
+    Diff,
+    /// A new palette for every frame.
+    Single,
+}
+
+impl StatsMode {
+    pub fn from(mode: &str) -> Result<StatsMode> {
+        match mode.to_lowercase().as_str() {
+            "full" => Ok(StatsMode::Full),
+            "diff" => Ok(StatsMode::Diff),
+            "single" => Ok(StatsMode::Single),
+            other => Err(InputError::Unknown {
+                what: "stats mode",
+                value: other.to_string(),
+                expected: "full, diff or single",
+            })?,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            StatsMode::Full => "full",
+            StatsMode::Diff => "diff",
+            StatsMode::Single => "single",
+        }
+    }
+}
+
+/// The two-pass palettegen/paletteuse pipeline for better looking gifs.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GifPalette {
+    pub dither: Dither,
+    /// From 2 to 256.
+    pub max_colors: u16,
+    pub stats_mode: StatsMode,
+}
+
+impl Default for GifPalette {
+    fn default() -> Self {
+        GifPalette {
+            dither: Dither::default(),
+            max_colors: 256,
+            stats_mode: StatsMode::default(),
+        }
+    }
+}
+
+impl GifPalette {
+    // Both passes in one filter graph, the frames go through palettegen and are
+    // then mapped on the palette it made.
+    fn filter(&self) -> String {
+        let new_palette = if self.stats_mode == StatsMode::Single {
+            ":new=1"
+        } else {
+            ""
+        };
+        format!(
+            "split[frames][stats];[stats]palettegen=max_colors={}:stats_mode={}[palette];\
+             [frames][palette]paletteuse=dither={}{}",
+            self.max_colors,
+            self.stats_mode.name(),
+            self.dither.name(),
+            new_palette
+        )
+    }
+}
+
+/// How a clip is encoded.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClipOptions {
+    pub format: ClipFormat,
+    /// The output width in pixels, the height keeps the aspect ratio.
+    pub width: u32,
+    /// The output frame rate, `None` keeps the one of the video.
+    pub fps: Option<u32>,
+    /// From 1 (smallest) to 100 (best), ignored by gif and apng.
+    pub quality: u8,
+    /// Build a palette for the gif first, slower but without the banding of
+    /// the default one. Only used by gif.
+    pub palette: Option<GifPalette>,
+}
+
+impl Default for ClipOptions {
+    fn default() -> Self {
+        ClipOptions {
+            format: ClipFormat::Gif,
+            width: 340,
+            fps: None,
+            quality: 75,
+            palette: None,
+        }
+    }
+}
+
+/// The image format of a single extracted frame.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum FrameFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl FrameFormat {
+    pub fn from(format: &str) -> Result<FrameFormat> {
+        match format.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(FrameFormat::Jpeg),
+            "png" => Ok(FrameFormat::Png),
+            "webp" => Ok(FrameFormat::Webp),
+            other => Err(InputError::Unknown {
+                what: "format",
+                value: other.to_string(),
+                expected: "jpeg, png or webp",
+            })?,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "image/jpeg",
+            FrameFormat::Png => "image/png",
+            FrameFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "jpg",
+            FrameFormat::Png => "png",
+            FrameFormat::Webp => "webp",
+        }
+    }
+
+    fn codec(self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "mjpeg",
+            FrameFormat::Png => "png",
+            FrameFormat::Webp => "libwebp",
+        }
+    }
+}
+
+/// Grab the frame of the video at `url` shown at `time`, scaled to `width` pixels.
+pub fn make_frame(
+    url: &str,
+    time: &Timestamp,
+    width: u32,
+    format: FrameFormat,
+    limits: &Limits,
+    cancel: &Cancel,
+) -> Result<Vec<u8>> {
+    let time: &str = &time.to_string();
+    let filter = format!("scale={}:-2", width);
+    let mut command = ffmpeg(limits);
+    command
+        .args(["-v", "error"])
+        .args(["-ss", time])
+        .args(["-i", url])
+        .args(["-frames:v", "1"])
+        .args(["-vf", &filter])
+        .args(["-c:v", format.codec()])
+        .args(["-f", "image2pipe"])
+        .arg("-hide_banner");
+    let mut body = Vec::new();
+    ffmpeg::run(
+        command,
+        "grabbing the frame",
+        limits,
+        cancel,
+        &mut body,
+        |_| false,
+    )?;
+    if body.is_empty() {
+        Err(err_msg(
+            "No frame at this time, is it after the end of the video ?",
+        ))?
+    }
+    Ok(body)
+}
+
+/// Transcode the audio at `url` to mp3 into `writer` as ffmpeg produces it,
+/// returns the number of bytes written. ffmpeg is killed when `writer` fails.
+pub fn transcode_mp3<W: Write>(
+    url: &str,
+    writer: &mut W,
+    limits: &Limits,
+    cancel: &Cancel,
+) -> Result<u64> {
+    let mut command = ffmpeg(limits);
+    command
+        .args(["-v", "error"])
+        .arg("-hide_banner")
+        .args(["-i", url])
+        .arg("-vn")
+        .args(["-c:a", "libmp3lame"])
+        .args(["-b:a", "192k"])
+        .args(["-f", "mp3"]);
+    ffmpeg::run(
+        command,
+        "transcoding to mp3",
+        limits,
+        cancel,
+        writer,
+        |_| false,
+    )
+}
+
+/// Cut `duration` of the video at `url` starting from `start` into a gif using ffmpeg.
+pub fn make_gif(url: &str, start: &Timestamp, duration: &Timestamp) -> Result<Vec<u8>> {
+    make_clip(
+        url,
+        start,
+        duration,
+        &ClipOptions::default(),
+        &Limits::default(),
+        &Cancel::new(),
+    )
+}
+
+/// Cut `duration` of the video at `url` starting from `start` into a silent clip using ffmpeg.
+pub fn make_clip(
+    url: &str,
+    start: &Timestamp,
+    duration: &Timestamp,
+    options: &ClipOptions,
+    limits: &Limits,
+    cancel: &Cancel,
+) -> Result<Vec<u8>> {
+    make_clip_with_progress(url, start, duration, options, limits, cancel, |_| {})
+}
+
+/// Like `make_clip`, calling `progress` with the done fraction of the clip, from 0 to 1,
+/// as ffmpeg reports it.
+pub fn make_clip_with_progress<F: FnMut(f64)>(
+    url: &str,
+    start: &Timestamp,
+    duration: &Timestamp,
+    options: &ClipOptions,
+    limits: &Limits,
+    cancel: &Cancel,
+    mut progress: F,
+) -> Result<Vec<u8>> {
+    let start_time: &str = &start.to_string();
+    let duration_str: &str = &duration.to_string();
+    // the encoders of mp4 and webm need an even height.
+    let mut filter = format!("scale={}:-2", options.width);
+    if let Some(fps) = options.fps {
+        filter = format!("fps={},{}", fps, filter);
+    }
+    if let (ClipFormat::Gif, Some(palette)) = (options.format, &options.palette) {
+        filter = format!("{},{}", filter, palette.filter());
+    }
+    let mut command = ffmpeg(limits);
+    command
+        .args(["-v", "error"])
+        .args(["-ss", start_time])
+        .args(["-t", duration_str])
+        .args(["-i", url])
+        .arg("-an")
+        .args(options.format.output_args(options.quality))
+        .arg("-hide_banner")
+        .args(["-vf", &filter])
+        .args(["-progress", "pipe:2"]);
+    let total = duration.as_secs_f64();
+    let what = format!("making a {}", options.format.extension());
+    let mut body = Vec::new();
+    ffmpeg::run(command, &what, limits, cancel, &mut body, |line| {
+        match progress_line(line) {
+            // the time of the last written frame, in microseconds despite the name.
+            Some(("out_time_us", value)) | Some(("out_time_ms", value)) if total > 0.0 => {
+                if let Ok(time) = value.parse::<f64>() {
+                    progress((time / 1_000_000.0 / total).clamp(0.0, 1.0));
+                }
+            }
+            Some(("progress", "end")) => progress(1.0),
+            Some(_) => {}
+            None => return false,
+        }
+        true
+    })?;
+    Ok(body)
+}
+
+// Split a `key=value` line of the ffmpeg `-progress` output, the other lines are errors.
+fn progress_line(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once('=')?;
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((key, value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every test sets the same path, they can run together.
+    fn fake_ffmpeg() {
+        set_ffmpeg_path(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/ffmpeg.sh"
+        ));
+    }
+
+    fn millis(time: &str) -> u64 {
+        Timestamp::from(time)
+            .unwrap_or_else(|e| panic!("{}: {}", time, e))
+            .millis()
+    }
+
+    #[test]
+    fn reads_every_time_format() {
+        assert_eq!(millis("1:02.5"), 62_500);
+        assert_eq!(millis("01:02:03"), 3_723_000);
+        assert_eq!(millis("90"), 90_000);
+        assert_eq!(millis("62.5"), 62_500);
+        assert_eq!(millis("0.0015"), 1);
+        assert_eq!(millis("90:00"), 5_400_000);
+        assert_eq!(millis("90s"), 90_000);
+        assert_eq!(millis("1m30s"), 90_000);
+        assert_eq!(millis("1h"), 3_600_000);
+        assert_eq!(millis("1h2m"), 3_720_000);
+        assert_eq!(millis("PT1M30.5S"), 90_500);
+        assert_eq!(millis("pt1m30,5s"), 90_500);
+        assert_eq!(millis("P1D"), 86_400_000);
+        assert_eq!(millis(" 90 "), 90_000);
+    }
+
+    #[test]
+    fn refuses_bad_times() {
+        let too_big = format!("{}", u64::MAX);
+        let hours = format!("{}h", u64::MAX / 1000);
+        for time in &[
+            "", "P", "PT", "1:60", "1:90:00", "1:2:3:4", "-1", "1.", "1m30", "abc", &too_big,
+            &hours,
+        ] {
+            assert!(Timestamp::from(time).is_err(), "{:?} was accepted", time);
+        }
+    }
+
+    #[test]
+    fn measures_the_time_between_two() {
+        let start = Timestamp::from("00:00:50").unwrap();
+        let end = Timestamp::from("00:01:10").unwrap();
+        assert_eq!(start.until(end).unwrap().millis(), 20_000);
+        assert!(end.until(start).is_err());
+        assert!(start.until(start).is_err());
+        assert_eq!(Timestamp::from_millis(1001).ceil_secs(), 2);
+        assert_eq!(Timestamp::from_millis(62_500).to_string(), "0:01:02.500");
+    }
+
+    #[test]
+    fn never_overflows() {
+        assert_eq!(Timestamp::from_secs(u64::MAX).millis(), u64::MAX);
+        let max = Timestamp::from_millis(u64::MAX);
+        assert_eq!((max + Timestamp::from_secs(1)).millis(), u64::MAX);
+        assert_eq!(Timestamp::from_millis(0).until(max).unwrap(), max);
+    }
+
+    #[test]
+    fn runs_the_configured_ffmpeg() {
+        fake_ffmpeg();
+        let limits = Limits {
+            threads: Some(3),
+            ..Limits::default()
+        };
+        let time = Timestamp::from_secs(1);
+        let frame = make_frame(
+            "frame",
+            &time,
+            320,
+            FrameFormat::Png,
+            &limits,
+            &Cancel::new(),
+        );
+        assert_eq!(frame.unwrap(), b"frame");
+        let args = make_frame(
+            "args",
+            &time,
+            320,
+            FrameFormat::Png,
+            &limits,
+            &Cancel::new(),
+        );
+        let args = String::from_utf8(args.unwrap()).unwrap();
+        assert!(
+            args.starts_with("-threads 3 -filter_threads 3 -v error -ss 0:00:01.000 -i args "),
+            "{}",
+            args
+        );
+        assert!(args.ends_with(" -threads 3 pipe:1"), "{}", args);
+    }
+
+    #[test]
+    fn reports_the_progress_of_a_clip() {
+        fake_ffmpeg();
+        let mut reported = Vec::new();
+        let clip = make_clip_with_progress(
+            "progress",
+            &Timestamp::from_secs(0),
+            &Timestamp::from_secs(1),
+            &ClipOptions::default(),
+            &Limits::default(),
+            &Cancel::new(),
+            |progress| reported.push(progress),
+        );
+        assert_eq!(clip.unwrap(), b"GIF89a");
+        assert_eq!(reported, vec![0.5, 1.0]);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::blocking;
+use crate::client::HttpClient;
+use crate::{BoxFuture, Result, VideoInfo, VideoSorces};
+use futures::Future;
+use log::debug;
+use roxmltree::{Document, Node};
+use url::Url;
+
+/// Parse a DASH MPD manifest into the same format model used for the regular sources,
+/// every representation becomes one format keyed by its itag.
+pub fn parse_mpd(mpd: &str, manifest_url: &str) -> Result<VideoSorces> {
+    let document = Document::parse(mpd)?;
+    let root = document.root_element();
+    let mpd_base = base_url(Url::parse(manifest_url)?, root)?;
+    let mut formats = VideoSorces::new();
+    for period in children(root, "Period") {
+        let period_base = base_url(mpd_base.clone(), period)?;
+        for set in children(period, "AdaptationSet") {
+            let set_base = base_url(period_base.clone(), set)?;
+            for representation in children(set, "Representation") {
+                let format = representation_format(&set_base, set, representation)?;
+                debug!("DASH format {:?}", format.get("itag"));
+                formats.push(format);
+            }
+        }
+    }
+    Ok(formats)
+}
+
+pub fn fetch_formats_async(client: &HttpClient, manifest_url: &str) -> BoxFuture<VideoSorces> {
+    let url = manifest_url.to_string();
+    let formats = client
+        .get_string_async(manifest_url)
+        .and_then(move |mpd| parse_mpd(&mpd, &url));
+    Box::new(formats)
+}
+
+pub fn fetch_formats(client: &HttpClient, manifest_url: &str) -> Result<VideoSorces> {
+    blocking::block_on(fetch_formats_async(client, manifest_url))
+}
+
+fn representation_format(base: &Url, set: Node, representation: Node) -> Result<VideoInfo> {
+    let mut format = VideoInfo::new();
+    let attribute = |name: &str| {
+        representation
+            .attribute(name)
+            .or_else(|| set.attribute(name))
+            .map(str::to_string)
+    };
+    let id = attribute("id").unwrap_or_default();
+    let bandwidth = attribute("bandwidth").unwrap_or_default();
+    format.insert("itag".to_string(), id.clone());
+    format.insert("bitrate".to_string(), bandwidth.clone());
+    if let Some(mime) = attribute("mimeType") {
+        let mime = match attribute("codecs") {
+            Some(codecs) => format!("{}; codecs=\"{}\"", mime, codecs),
+            None => mime,
+        };
+        format.insert("type".to_string(), mime);
+    }
+    if let (Some(width), Some(height)) = (attribute("width"), attribute("height")) {
+        format.insert("size".to_string(), format!("{}x{}", width, height));
+    }
+    if let Some(fps) = attribute("frameRate") {
+        format.insert("fps".to_string(), fps);
+    }
+    if let Some(rate) = attribute("audioSamplingRate") {
+        format.insert("audio_sample_rate".to_string(), rate);
+    }
+
+    let base = base_url(base.clone(), representation)?;
+    format.insert("url".to_string(), base.to_string());
+    if let Some(length) =
+        child(representation, "BaseURL").and_then(|b| local_attribute(b, "contentLength"))
+    {
+        format.insert("clen".to_string(), length.to_string());
+    }
+
+    // byte ranges into the single file at the base url.
+    if let Some(segment_base) = child(representation, "SegmentBase") {
+        if let Some(index) = segment_base.attribute("indexRange") {
+            format.insert("index".to_string(), index.to_string());
+        }
+        if let Some(init) = child(segment_base, "Initialization").and_then(|i| i.attribute("range"))
+        {
+            format.insert("init".to_string(), init.to_string());
+        }
+    }
+
+    // or a list of segment urls.
+    if let Some(list) = child(representation, "SegmentList").or_else(|| child(set, "SegmentList")) {
+        if let Some(init) = child(list, "Initialization").and_then(|i| i.attribute("sourceURL")) {
+            format.insert(
+                "segment_initialization".to_string(),
+                base.join(init)?.to_string(),
+            );
+        }
+        let mut segments = Vec::new();
+        for segment in children(list, "SegmentURL") {
+            if let Some(media) = segment.attribute("media") {
+                segments.push(base.join(media)?.to_string());
+            }
+        }
+        format.insert("segment_list".to_string(), segments.join(","));
+    }
+
+    // or a template to build them from.
+    let template =
+        child(representation, "SegmentTemplate").or_else(|| child(set, "SegmentTemplate"));
+    if let Some(template) = template {
+        let expand = |value: &str| {
+            value
+                .replace("$RepresentationID$", &id)
+                .replace("$Bandwidth$", &bandwidth)
+        };
+        if let Some(media) = template.attribute("media") {
+            format.insert(
+                "segment_template".to_string(),
+                base.join(&expand(media))?.to_string(),
+            );
+        }
+        if let Some(init) = template.attribute("initialization") {
+            format.insert(
+                "segment_initialization".to_string(),
+                base.join(&expand(init))?.to_string(),
+            );
+        }
+        for (attribute, key) in &[
+            ("startNumber", "start_number"),
+            ("timescale", "timescale"),
+            ("duration", "segment_duration"),
+        ] {
+            if let Some(value) = template.attribute(*attribute) {
+                format.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+    Ok(format)
+}
+
+// Resolve the BaseURL child of node, if any, against the parent base.
+fn base_url(parent: Url, node: Node) -> Result<Url> {
+    match child(node, "BaseURL").and_then(|b| b.text()) {
+        Some(text) => Ok(parent.join(text.trim())?),
+        None => Ok(parent),
+    }
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn child<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'static str) -> Option<Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+// Get an attribute by its local name, ignoring the namespace (like `yt:contentLength`).
+fn local_attribute<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attributes()
+        .iter()
+        .find(|a| a.name() == name)
+        .map(|a| a.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST_URL: &str = "https://manifest.googlevideo.com/api/manifest/dash/id/abc";
+
+    fn format<'a>(formats: &'a VideoSorces, itag: &str) -> &'a VideoInfo {
+        formats
+            .iter()
+            .find(|format| format["itag"] == itag)
+            .unwrap_or_else(|| panic!("No format {}", itag))
+    }
+
+    #[test]
+    fn segment_base() {
+        let mpd = include_str!("../tests/fixtures/segment_base.mpd");
+        let formats = parse_mpd(mpd, MANIFEST_URL).unwrap();
+        assert_eq!(formats.len(), 2);
+        let audio = format(&formats, "140");
+        assert_eq!(audio["type"], "audio/mp4; codecs=\"mp4a.40.2\"");
+        assert_eq!(audio["bitrate"], "130000");
+        assert_eq!(audio["audio_sample_rate"], "44100");
+        assert_eq!(
+            audio["url"],
+            "https://r1.googlevideo.com/videoplayback/id/abc/itag/140/source/youtube/"
+        );
+        assert_eq!(audio["clen"], "3436431");
+        assert_eq!(audio["init"], "0-591");
+        assert_eq!(audio["index"], "592-867");
+        let video = format(&formats, "137");
+        assert_eq!(video["size"], "1920x1080");
+        assert_eq!(video["fps"], "30");
+        assert_eq!(video["clen"], "99514231");
+        assert!(!video.contains_key("segment_template"));
+    }
+
+    #[test]
+    fn segment_template_and_nested_base_urls() {
+        let mpd = include_str!("../tests/fixtures/segment_template.mpd");
+        let formats = parse_mpd(mpd, MANIFEST_URL).unwrap();
+        let hd = format(&formats, "720p");
+        // the codecs and the template come from the adaptation set.
+        assert_eq!(hd["type"], "video/mp4; codecs=\"avc1.4d401f\"");
+        assert_eq!(hd["fps"], "25");
+        assert_eq!(hd["url"], "https://cdn.example.com/live/period0/");
+        assert_eq!(
+            hd["segment_template"],
+            "https://cdn.example.com/live/period0/720p/seg-$Number$.m4s"
+        );
+        assert_eq!(
+            hd["segment_initialization"],
+            "https://cdn.example.com/live/period0/720p/init-2500000.mp4"
+        );
+        assert_eq!(hd["start_number"], "7");
+        assert_eq!(hd["timescale"], "1000");
+        assert_eq!(hd["segment_duration"], "2000");
+        // an absolute path BaseURL replaces the path of its parents.
+        let sd = format(&formats, "360p");
+        assert_eq!(sd["url"], "https://cdn.example.com/other/");
+        assert_eq!(
+            sd["segment_initialization"],
+            "https://cdn.example.com/other/360p/init-800000.mp4"
+        );
+    }
+
+    #[test]
+    fn segment_list_relative_to_the_manifest() {
+        let mpd = include_str!("../tests/fixtures/segment_list.mpd");
+        let formats = parse_mpd(mpd, "https://example.com/dash/manifest.mpd").unwrap();
+        let audio = format(&formats, "251");
+        assert_eq!(audio["url"], "https://example.com/dash/audio/");
+        assert_eq!(
+            audio["segment_initialization"],
+            "https://example.com/dash/audio/init.webm"
+        );
+        assert_eq!(
+            audio["segment_list"],
+            "https://example.com/dash/audio/1.webm,https://example.com/dash/audio/2.webm"
+        );
+        assert!(!audio.contains_key("clen"));
+    }
+
+    #[test]
+    fn not_a_manifest() {
+        assert!(parse_mpd("not xml", MANIFEST_URL).is_err());
+        assert!(parse_mpd("<MPD/>", "not a url").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use failure::Fail;
+use std::fmt;
+use std::time::Duration;
+
+// what ffmpeg writes when it cannot read its input, lowercased.
+const INPUT_FAILURES: [&str; 7] = [
+    "server returned",
+    "http error",
+    "connection refused",
+    "connection timed out",
+    "failed to resolve",
+    "input/output error",
+    "invalid data found when processing input",
+];
+
+/// Errors that callers may want to tell apart from the generic failures.
+#[derive(Debug)]
+pub enum VideoError {
+    /// The video is age restricted and the embedded player fallback failed too.
+    AgeRestricted { reason: String },
+    /// Only its owner and who they shared it with can watch the video.
+    Private { reason: String },
+    /// The video doesn't exist, was removed or is blocked.
+    Unavailable { reason: String },
+}
+
+impl fmt::Display for VideoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VideoError::AgeRestricted { reason } => write!(
+                f,
+                "Video is age restricted and the embedded player fallback failed: {}",
+                reason
+            ),
+            VideoError::Private { reason } => write!(f, "Video is private: {}", reason),
+            VideoError::Unavailable { reason } => write!(f, "Video is unavailable: {}", reason),
+        }
+    }
+}
+
+impl Fail for VideoError {}
+
+/// Bad values given by the caller, like a time that can't be parsed.
+#[derive(Debug)]
+pub enum InputError {
+    /// A time that can't be parsed, or an end before its start.
+    Time { reason: String },
+    /// A value that is not one of the `expected` ones, like an unknown format.
+    Unknown {
+        what: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::Time { reason } => write!(f, "{}", reason),
+            InputError::Unknown {
+                what,
+                value,
+                expected,
+            } => write!(
+                f,
+                "Unknown {} '{}', it should be one of {}",
+                what, value, expected
+            ),
+        }
+    }
+}
+
+impl Fail for InputError {}
+
+/// Failures of the http requests, once the retry policy gave up on them.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The server answered with a status that retrying won't fix, like a 404.
+    Status {
+        url: String,
+        status: u16,
+        attempts: u32,
+    },
+    /// The request kept failing with retryable errors until we ran out of attempts.
+    RetriesExhausted {
+        url: String,
+        attempts: u32,
+        reason: String,
+    },
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Status {
+                url,
+                status,
+                attempts,
+            } => write!(
+                f,
+                "Request to {} failed with status {} after {} attempt(s)",
+                url, status, attempts
+            ),
+            FetchError::RetriesExhausted {
+                url,
+                attempts,
+                reason,
+            } => write!(
+                f,
+                "Request to {} failed after {} attempt(s), last error: {}",
+                url, attempts, reason
+            ),
+        }
+    }
+}
+
+impl Fail for FetchError {}
+
+/// Why an ffmpeg run failed, `what` tells what it was doing, like `making a gif`.
+#[derive(Debug)]
+pub enum FfmpegError {
+    /// ffmpeg exited with an error, `stderr` has the last lines it wrote.
+    Failed {
+        what: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    /// ffmpeg ran longer than the limit and was killed, often a stuck download.
+    TimedOut {
+        what: String,
+        after: Duration,
+        stderr: String,
+    },
+    /// ffmpeg wrote nothing for `after` and was killed, the input likely stopped.
+    Stalled {
+        what: String,
+        after: Duration,
+        stderr: String,
+    },
+    /// ffmpeg wrote more than `limit` bytes and was killed.
+    OutputTooLarge { what: String, limit: u64 },
+    /// The run was cancelled, like when its client went away.
+    Cancelled { what: String },
+}
+
+impl fmt::Display for FfmpegError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FfmpegError::Failed { what, code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "ffmpeg failed {} with exit code {}", what, code)?,
+                    None => write!(f, "ffmpeg was killed {}", what)?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+            FfmpegError::TimedOut { what, after, .. } => write!(
+                f,
+                "ffmpeg took longer than {} seconds {}, maybe a slow video url ?",
+                after.as_secs(),
+                what
+            ),
+            FfmpegError::Stalled { what, after, .. } => write!(
+                f,
+                "ffmpeg wrote nothing for {} seconds {}, maybe a stuck video url ?",
+                after.as_secs(),
+                what
+            ),
+            FfmpegError::OutputTooLarge { what, limit } => write!(
+                f,
+                "ffmpeg wrote more than {} bytes {}, try a shorter or smaller one",
+                limit, what
+            ),
+            FfmpegError::Cancelled { what } => write!(f, "ffmpeg was cancelled {}", what),
+        }
+    }
+}
+
+impl Fail for FfmpegError {}
+
+impl FfmpegError {
+    /// Whether ffmpeg failed to read its input, like a video url answering
+    /// 403, rather than on its own.
+    pub fn is_input_failure(&self) -> bool {
+        match self {
+            FfmpegError::Failed { stderr, .. } => {
+                let stderr = stderr.to_lowercase();
+                INPUT_FAILURES
+                    .iter()
+                    .any(|failure| stderr.contains(failure))
+            }
+            _ => false,
+        }
+    }
+
+    /// The last lines ffmpeg wrote to stderr, when it got to run.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            FfmpegError::Failed { stderr, .. }
+            | FfmpegError::TimedOut { stderr, .. }
+            | FfmpegError::Stalled { stderr, .. } => Some(stderr),
+            FfmpegError::OutputTooLarge { .. } | FfmpegError::Cancelled { .. } => None,
+        }
+    }
+}
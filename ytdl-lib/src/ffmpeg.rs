@@ -0,0 +1,336 @@
+use crate::error::FfmpegError;
+use crate::Result;
+use failure::err_msg;
+use log::debug;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often a run checks its deadline and whether it was cancelled.
+const TICK: Duration = Duration::from_millis(100);
+// the chunks of output read ahead of the writer before ffmpeg blocks.
+const CHANNEL_CHUNKS: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+// the stderr lines kept for the error, ffmpeg tells why it failed at the end.
+const STDERR_LINES: usize = 20;
+
+/// The bounds of an ffmpeg run, the default has none.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Kill ffmpeg once it ran this long.
+    pub timeout: Option<Duration>,
+    /// Kill ffmpeg once it wrote nothing for this long, for the streamed runs
+    /// that last as long as their input.
+    pub idle_timeout: Option<Duration>,
+    /// Passed as `-threads` to the input and the output and as
+    /// `-filter_threads`, ffmpeg picks by itself without it.
+    pub threads: Option<u32>,
+    /// Kill ffmpeg once it wrote more bytes than this.
+    pub max_output: Option<u64>,
+}
+
+/// Stops a run from another thread, like when its client went away. The
+/// clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Cancel::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+enum Output {
+    Stdout(Vec<u8>),
+    Stderr(String),
+}
+
+/// The options going before `-i`, for the threads of the input and the
+/// filters.
+pub(crate) fn input_args(limits: &Limits) -> Vec<String> {
+    match limits.threads {
+        Some(threads) => vec![
+            "-threads".to_string(),
+            threads.to_string(),
+            "-filter_threads".to_string(),
+            threads.to_string(),
+        ],
+        None => Vec::new(),
+    }
+}
+
+/// Run `command` writing to `pipe:1` into `out`, returns the number of bytes
+/// written. `on_stderr` sees every stderr line and returns whether it used
+/// it, the other lines end up in the error. ffmpeg is killed once out of
+/// `limits`, cancelled or when `out` fails.
+pub(crate) fn run<W, F>(
+    mut command: Command,
+    what: &str,
+    limits: &Limits,
+    cancel: &Cancel,
+    out: &mut W,
+    mut on_stderr: F,
+) -> Result<u64>
+where
+    W: Write,
+    F: FnMut(&str) -> bool,
+{
+    // the input side gets its threads from `input_args`.
+    if let Some(threads) = limits.threads {
+        command.args(["-threads", &threads.to_string()]);
+    }
+    let mut child = command
+        .arg("pipe:1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let started = Instant::now();
+    let mut last_output = started;
+    // both pipes are read on the side so ffmpeg never blocks on a full one,
+    // and this thread is free to watch the clock.
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_CHUNKS);
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| err_msg("ffmpeg has no stdout"))?;
+    let stdout_sender = sender.clone();
+    let stdout_reader = thread::spawn(move || -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let read = stdout.read(&mut chunk)?;
+            if read == 0
+                || stdout_sender
+                    .send(Output::Stdout(chunk[..read].to_vec()))
+                    .is_err()
+            {
+                return Ok(());
+            }
+        }
+    });
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| err_msg("ffmpeg has no stderr"))?;
+    let stderr_reader = thread::spawn(move || -> io::Result<()> {
+        for line in BufReader::new(stderr).lines() {
+            if sender.send(Output::Stderr(line?)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+    let mut written = 0;
+    let mut errors = VecDeque::with_capacity(STDERR_LINES);
+    let failure: Option<failure::Error> = loop {
+        match receiver.recv_timeout(TICK) {
+            Ok(Output::Stdout(chunk)) => {
+                written += chunk.len() as u64;
+                if let Some(limit) = limits.max_output.filter(|&limit| written > limit) {
+                    break Some(
+                        FfmpegError::OutputTooLarge {
+                            what: what.to_string(),
+                            limit,
+                        }
+                        .into(),
+                    );
+                }
+                if let Err(error) = out.write_all(&chunk) {
+                    break Some(error.into());
+                }
+                // a slow reader is not a stuck ffmpeg.
+                last_output = Instant::now();
+            }
+            Ok(Output::Stderr(line)) => {
+                if !on_stderr(&line) {
+                    if errors.len() == STDERR_LINES {
+                        errors.pop_front();
+                    }
+                    errors.push_back(line);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break None,
+        }
+        if cancel.is_cancelled() {
+            break Some(
+                FfmpegError::Cancelled {
+                    what: what.to_string(),
+                }
+                .into(),
+            );
+        }
+        if let Some(timeout) = limits
+            .timeout
+            .filter(|&timeout| started.elapsed() > timeout)
+        {
+            break Some(
+                FfmpegError::TimedOut {
+                    what: what.to_string(),
+                    after: timeout,
+                    stderr: Vec::from(errors.clone()).join("\n"),
+                }
+                .into(),
+            );
+        }
+        if let Some(idle) = limits
+            .idle_timeout
+            .filter(|&idle| last_output.elapsed() > idle)
+        {
+            break Some(
+                FfmpegError::Stalled {
+                    what: what.to_string(),
+                    after: idle,
+                    stderr: Vec::from(errors.clone()).join("\n"),
+                }
+                .into(),
+            );
+        }
+    };
+    if let Some(error) = failure {
+        debug!("Killing ffmpeg: {}", error);
+        // it may have exited on its own in the meantime.
+        let _ = child.kill();
+        // unblock the readers waiting on a full channel.
+        drop(receiver);
+        child.wait()?;
+        let _ = stdout_reader.join();
+        let _ = stderr_reader.join();
+        return Err(error);
+    }
+    let status = child.wait()?;
+    stdout_reader
+        .join()
+        .map_err(|_| err_msg("The ffmpeg stdout reader panicked"))??;
+    stderr_reader
+        .join()
+        .map_err(|_| err_msg("The ffmpeg stderr reader panicked"))??;
+    if !status.success() {
+        Err(FfmpegError::Failed {
+            what: what.to_string(),
+            code: status.code(),
+            stderr: Vec::from(errors).join("\n"),
+        })?
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAKE_FFMPEG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ffmpeg.sh");
+
+    fn run_fake(input: &str, limits: &Limits, cancel: &Cancel) -> (Result<u64>, Vec<u8>) {
+        let mut command = Command::new(FAKE_FFMPEG);
+        command.args(input_args(limits)).args(["-i", input]);
+        let mut out = Vec::new();
+        let result = run(command, "testing", limits, cancel, &mut out, |line| {
+            line.starts_with("progress=")
+        });
+        (result, out)
+    }
+
+    fn ffmpeg_error(result: Result<u64>) -> FfmpegError {
+        result.unwrap_err().downcast::<FfmpegError>().unwrap()
+    }
+
+    #[test]
+    fn passes_the_threads_around_the_input() {
+        let limits = Limits {
+            threads: Some(2),
+            ..Limits::default()
+        };
+        let (result, out) = run_fake("args", &limits, &Cancel::new());
+        assert_eq!(result.unwrap(), out.len() as u64);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "-threads 2 -filter_threads 2 -i args -threads 2 pipe:1"
+        );
+    }
+
+    #[test]
+    fn keeps_the_stderr_of_a_failure() {
+        let (result, _) = run_fake("fail", &Limits::default(), &Cancel::new());
+        match ffmpeg_error(result) {
+            error @ FfmpegError::Failed { code: Some(1), .. } => {
+                assert!(error.is_input_failure());
+                assert_eq!(error.stderr().unwrap().lines().count(), 2);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn kills_a_run_past_its_timeout() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(300)),
+            ..Limits::default()
+        };
+        let started = Instant::now();
+        let (result, _) = run_fake("hang", &limits, &Cancel::new());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        match ffmpeg_error(result) {
+            FfmpegError::TimedOut { after, .. } => assert_eq!(after, Duration::from_millis(300)),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn kills_a_stream_writing_nothing() {
+        let limits = Limits {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Limits::default()
+        };
+        let (result, out) = run_fake("stall", &limits, &Cancel::new());
+        assert_eq!(out, b"ID3");
+        match ffmpeg_error(result) {
+            FfmpegError::Stalled { .. } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn kills_a_run_writing_too_much() {
+        let limits = Limits {
+            max_output: Some(1000),
+            ..Limits::default()
+        };
+        let (result, out) = run_fake("large", &limits, &Cancel::new());
+        assert!(out.len() <= 1000);
+        match ffmpeg_error(result) {
+            FfmpegError::OutputTooLarge { limit: 1000, .. } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn kills_a_cancelled_run() {
+        let cancel = Cancel::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            canceller.cancel();
+        });
+        let started = Instant::now();
+        let (result, _) = run_fake("hang", &Limits::default(), &cancel);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        match ffmpeg_error(result) {
+            FfmpegError::Cancelled { .. } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use crate::client::HttpClient;
+use crate::Result;
+use failure::{err_msg, format_err};
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+const EXTM3U: &str = "#EXTM3U";
+const STREAM_INF: &str = "#EXT-X-STREAM-INF:";
+const TARGET_DURATION: &str = "#EXT-X-TARGETDURATION:";
+const MEDIA_SEQUENCE: &str = "#EXT-X-MEDIA-SEQUENCE:";
+const EXTINF: &str = "#EXTINF:";
+const ENDLIST: &str = "#EXT-X-ENDLIST";
+// how often we check the stop flag while waiting for new segments.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// the shortest wait between two reloads of the playlist, even without a target duration.
+const MIN_RELOAD_WAIT: Duration = Duration::from_secs(1);
+// the downloads of a segment before we skip it.
+const SEGMENT_ATTEMPTS: u32 = 3;
+
+/// A stream listed in a master playlist.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Variant {
+    pub url: String,
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+    pub frame_rate: Option<f64>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Segment {
+    pub sequence: u64,
+    pub duration: f64,
+    pub url: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone, Default)]
+pub struct MediaPlaylist {
+    pub target_duration: f64,
+    pub media_sequence: u64,
+    pub segments: Vec<Segment>,
+    /// Set once the stream is over and no more segments will be added.
+    pub ended: bool,
+}
+
+/// Parse a master playlist into its variants, sorted from the highest bandwidth.
+pub fn parse_master(playlist: &str, base_url: &str) -> Result<Vec<Variant>> {
+    let base = Url::parse(base_url)?;
+    let mut lines = playlist_lines(playlist)?;
+    let mut variants = Vec::new();
+    while let Some(line) = lines.next() {
+        let attributes = match line.strip_prefix(STREAM_INF) {
+            Some(list) => parse_attributes(list),
+            None => continue,
+        };
+        let uri = lines
+            .find(|l| !l.starts_with('#'))
+            .ok_or_else(|| err_msg("EXT-X-STREAM-INF without a uri"))?;
+        let resolution = attributes.get("RESOLUTION").and_then(|r| {
+            let mut parts = r.split('x').map(str::parse::<u32>);
+            match (parts.next(), parts.next()) {
+                (Some(Ok(w)), Some(Ok(h))) => Some((w, h)),
+                _ => None,
+            }
+        });
+        variants.push(Variant {
+            url: base.join(uri)?.into_string(),
+            bandwidth: attributes
+                .get("BANDWIDTH")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0),
+            resolution,
+            codecs: attributes.get("CODECS").cloned(),
+            frame_rate: attributes.get("FRAME-RATE").and_then(|f| f.parse().ok()),
+        });
+    }
+    variants.sort_by_key(|variant| std::cmp::Reverse(variant.bandwidth));
+    Ok(variants)
+}
+
+/// Parse a media playlist into its segments.
+pub fn parse_media(playlist: &str, base_url: &str) -> Result<MediaPlaylist> {
+    let base = Url::parse(base_url)?;
+    let mut media = MediaPlaylist::default();
+    let mut duration = None;
+    for line in playlist_lines(playlist)? {
+        if let Some(value) = line.strip_prefix(TARGET_DURATION) {
+            media.target_duration = value.parse()?;
+        } else if let Some(value) = line.strip_prefix(MEDIA_SEQUENCE) {
+            media.media_sequence = value.parse()?;
+        } else if let Some(value) = line.strip_prefix(EXTINF) {
+            let value = value.split(',').next().unwrap_or_default();
+            duration = Some(value.parse()?);
+        } else if line.starts_with(ENDLIST) {
+            media.ended = true;
+        } else if !line.starts_with('#') {
+            let sequence = media.media_sequence + media.segments.len() as u64;
+            media.segments.push(Segment {
+                sequence,
+                duration: duration.take().unwrap_or(media.target_duration),
+                url: base.join(line)?.into_string(),
+            });
+        }
+    }
+    Ok(media)
+}
+
+pub fn fetch_variants(client: &HttpClient, master_url: &str) -> Result<Vec<Variant>> {
+    parse_master(&client.get_string(master_url)?, master_url)
+}
+
+pub fn fetch_media(client: &HttpClient, media_url: &str) -> Result<MediaPlaylist> {
+    parse_media(&client.get_string(media_url)?, media_url)
+}
+
+/// Record a live media playlist into `writer` until `stop` is set or the stream ends,
+/// returns the number of bytes written.
+pub fn record<W: Write>(
+    client: &HttpClient,
+    media_url: &str,
+    writer: &mut W,
+    stop: &AtomicBool,
+) -> Result<u64> {
+    let mut next_sequence = 0;
+    let mut written = 0;
+    while !stop.load(Ordering::SeqCst) {
+        let media = fetch_media(client, media_url)?;
+        if let Some(first) = media.segments.first() {
+            if next_sequence != 0 && first.sequence > next_sequence {
+                warn!(
+                    "Missed {} live segments, the playlist moved faster than us",
+                    first.sequence - next_sequence
+                );
+            }
+        }
+        let pending: Vec<&Segment> = media
+            .segments
+            .iter()
+            .filter(|s| s.sequence >= next_sequence)
+            .collect();
+        for segment in pending {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            next_sequence = segment.sequence + 1;
+            // fetched whole so a failed download leaves no partial segment behind.
+            if let Some(body) = fetch_segment(client, segment) {
+                writer.write_all(&body)?;
+                written += body.len() as u64;
+            }
+        }
+        if media.ended {
+            info!("Live stream ended after {} bytes", written);
+            break;
+        }
+        let mut wait = reload_wait(media.target_duration);
+        while wait > Duration::from_millis(0) && !stop.load(Ordering::SeqCst) {
+            let step = wait.min(STOP_POLL_INTERVAL);
+            thread::sleep(step);
+            wait -= step;
+        }
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+// Download a segment, `None` once it failed too many times and is skipped.
+fn fetch_segment(client: &HttpClient, segment: &Segment) -> Option<Vec<u8>> {
+    for attempt in 1..=SEGMENT_ATTEMPTS {
+        debug!("Downloading segment {}", segment.sequence);
+        match client.get_bytes(&segment.url) {
+            Ok(body) => return Some(body),
+            Err(error) => warn!(
+                "Segment {} failed (attempt {}/{}): {}",
+                segment.sequence, attempt, SEGMENT_ATTEMPTS, error
+            ),
+        }
+    }
+    warn!("Skipping segment {}", segment.sequence);
+    None
+}
+
+// New segments show up about every target duration, wait half of it.
+fn reload_wait(target_duration: f64) -> Duration {
+    Duration::from_millis((target_duration.max(0.0) * 500.0) as u64).max(MIN_RELOAD_WAIT)
+}
+
+fn playlist_lines(playlist: &str) -> Result<impl Iterator<Item = &str>> {
+    let mut lines = playlist.lines().map(str::trim).filter(|l| !l.is_empty());
+    match lines.next() {
+        Some(EXTM3U) => Ok(lines),
+        other => Err(format_err!("Not a m3u8 playlist, starts with {:?}", other)),
+    }
+}
+
+// Parse an attribute list like `BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2"`.
+fn parse_attributes(list: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = list;
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"').map_or(rest.len(), |end| end + 1);
+            let value = &rest[1..end];
+            rest = rest.get(end + 1..).unwrap_or_default();
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        attributes.insert(key, value.to_string());
+        rest = rest.trim_start_matches(',');
+    }
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,RESOLUTION=1280x720,FRAME-RATE=30
+https://cdn.example.com/high/index.m3u8
+";
+
+    #[test]
+    fn master_variants_are_sorted_and_resolved() {
+        let variants = parse_master(MASTER, "https://example.com/live/master.m3u8").unwrap();
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].url, "https://cdn.example.com/high/index.m3u8");
+        assert_eq!(variants[0].bandwidth, 2_560_000);
+        assert_eq!(variants[0].resolution, Some((1280, 720)));
+        assert_eq!(variants[0].frame_rate, Some(30.0));
+        assert_eq!(variants[1].url, "https://example.com/live/low/index.m3u8");
+        assert_eq!(variants[1].codecs.as_deref(), Some("avc1.4d401e,mp4a.40.2"));
+    }
+
+    #[test]
+    fn master_rejects_a_stream_without_uri() {
+        let playlist = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\n";
+        assert!(parse_master(playlist, "https://example.com/").is_err());
+    }
+
+    #[test]
+    fn not_a_playlist() {
+        assert!(parse_media("<html>", "https://example.com/").is_err());
+        assert!(parse_master("", "https://example.com/").is_err());
+    }
+
+    #[test]
+    fn media_segments_are_numbered() {
+        let playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:42
+#EXTINF:5.5,
+seg42.ts
+#EXTINF:6.0,title
+seg43.ts
+seg44.ts
+";
+        let media = parse_media(playlist, "https://example.com/live/index.m3u8").unwrap();
+        assert_eq!(media.target_duration, 6.0);
+        assert_eq!(media.media_sequence, 42);
+        assert!(!media.ended);
+        let segments: Vec<_> = media
+            .segments
+            .iter()
+            .map(|s| (s.sequence, s.duration, s.url.as_str()))
+            .collect();
+        assert_eq!(
+            segments,
+            vec![
+                (42, 5.5, "https://example.com/live/seg42.ts"),
+                (43, 6.0, "https://example.com/live/seg43.ts"),
+                // no EXTINF, it lasts the target duration.
+                (44, 6.0, "https://example.com/live/seg44.ts"),
+            ]
+        );
+    }
+
+    #[test]
+    fn media_endlist() {
+        let playlist = "#EXTM3U\n#EXTINF:2,\na.ts\n#EXT-X-ENDLIST\n";
+        let media = parse_media(playlist, "https://example.com/").unwrap();
+        assert!(media.ended);
+        assert_eq!(media.target_duration, 0.0);
+        assert_eq!(media.segments.len(), 1);
+    }
+
+    #[test]
+    fn reload_wait_has_a_minimum() {
+        assert_eq!(reload_wait(0.0), MIN_RELOAD_WAIT);
+        assert_eq!(reload_wait(-1.0), MIN_RELOAD_WAIT);
+        assert_eq!(reload_wait(6.0), Duration::from_secs(3));
+    }
+}
@@ -1,25 +1,47 @@
+mod blocking;
+pub mod client;
+pub mod clip;
+pub mod dash;
+pub mod error;
+pub mod ffmpeg;
+pub mod hls;
 pub mod video_model;
-use crate::video_model::VideoConfig;
+use crate::client::{ClientConfig, HttpClient};
+use crate::error::VideoError;
+use crate::hls::Variant;
+use crate::video_model::{CaptionTrack, InfoPath, PlayerResponse, VideoConfig};
 use failure::format_err;
 use failure::{err_msg, Error};
+use futures::{future, stream, Future, Stream};
 use lazy_static::lazy_static;
 use log::{debug, error, info, warn};
 use regex::{Captures, Regex};
-use reqwest::Response;
 use std::collections::HashMap;
 use std::fmt;
-use std::io::Read;
-use std::iter::FromIterator;
+use std::io::Write;
+use std::iter::{self, FromIterator};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
-use url::form_urlencoded::parse;
+use url::form_urlencoded::{parse, Serializer};
+
+pub use reqwest::r#async::Chunk;
 
 type Result<T> = std::result::Result<T, Error>;
-type VideoInfo = HashMap<String, String>;
-type VideoSorces = Vec<VideoInfo>;
+pub type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+pub type BoxStream<T> = Box<dyn Stream<Item = T, Error = Error> + Send>;
+pub type VideoInfo = HashMap<String, String>;
+pub type VideoSorces = Vec<VideoInfo>;
 type TokensContainer = HashMap<String, Vec<(String, usize)>>;
 
 const YOUTUBE_INFO_URL: &str = "https://www.youtube.com/get_video_info";
 const YOUTUBE_BASE_URL: &str = "https://www.youtube.com";
+const YOUTUBE_EMBED_REFERER: &str = "https://youtube.googleapis.com/v/";
+const AGE_GATE_REASONS: [&str; 3] = [
+    "confirm your age",
+    "age-restricted",
+    "inappropriate for some users",
+];
 const JS_VAR_STR: &str = "[a-zA-Z_\\$][a-zA-Z_0-9]*";
 const JS_SINGLE_QUOTE: &str = "'[^'\\\\]*(:?\\\\[\\s\\S][^'\\\\]*)*'";
 const JS_DOUBLE_QUOTE: &str = r#""[^"\\]*(:?\\[\s\S][^"\\]*)*""#;
@@ -67,8 +89,13 @@ lazy_static! {
     static ref SLICE_REGEX: Regex = Regex::new(&SLICE_REGEX_STR).unwrap();
     static ref SPLICE_REGEX: Regex = Regex::new(&SPLICE_REGEX_STR).unwrap();
     static ref SWAP_REGEX: Regex = Regex::new(&SWAP_REGEX_STR).unwrap();
+    static ref DASH_SIGNATURE_REGEX: Regex = Regex::new(r"/s/([a-fA-F0-9\.]+)").unwrap();
 }
 
+// how often the tokens of a player were found in TOKENSCONTAINER.
+static TOKEN_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static TOKEN_CACHE_MISSES: AtomicUsize = AtomicUsize::new(0);
+
 impl fmt::Display for JS_QUOTE_STR {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.as_str())
@@ -90,8 +117,11 @@ pub struct Video {
     id: String,
     info: VideoInfo,
     config: VideoConfig,
+    player_response: PlayerResponse,
     initialized: bool,
     sources: VideoSorces,
+    adaptive_sources: VideoSorces,
+    client: HttpClient,
 }
 
 impl Video {
@@ -100,11 +130,22 @@ impl Video {
             id: id.to_string(),
             info: VideoInfo::new(),
             config: VideoConfig::default(),
+            player_response: PlayerResponse::default(),
             sources: VideoSorces::new(),
+            adaptive_sources: VideoSorces::new(),
             initialized: false,
+            client: HttpClient::default(),
         }
     }
 
+    /// Like `new`, but every request goes through a client built from `config`.
+    pub fn with_config(id: &str, config: &ClientConfig) -> Result<Self> {
+        Ok(Video {
+            client: HttpClient::new(config)?,
+            ..Video::new(id)
+        })
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -118,6 +159,114 @@ impl Video {
         }
     }
 
+    /// The audio-only and video-only (DASH) sources of the video.
+    pub fn adaptive_sources(&self) -> Option<&VideoSorces> {
+        if self.initialized {
+            Some(&self.adaptive_sources)
+        } else {
+            error!("Video not initialized !");
+            None
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.info.get("title").map(String::as_str)
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.info.get("author").map(String::as_str)
+    }
+
+    pub fn length_seconds(&self) -> Option<u64> {
+        self.info.get("length_seconds").and_then(|l| l.parse().ok())
+    }
+
+    /// Whether the video is a live broadcast, these only have an HLS manifest.
+    pub fn is_live(&self) -> bool {
+        self.info.get("live_playback").map(String::as_str) == Some("1")
+            || self.player_response.video_details.is_live
+    }
+
+    pub fn hls_manifest_url(&self) -> Option<&str> {
+        let streaming_data = &self.player_response.streaming_data;
+        match self.info.get("hlsvp") {
+            Some(url) => Some(url),
+            None if !streaming_data.hls_manifest_url.is_empty() => {
+                Some(&streaming_data.hls_manifest_url)
+            }
+            None => None,
+        }
+    }
+
+    pub fn dash_manifest_url(&self) -> Option<&str> {
+        let streaming_data = &self.player_response.streaming_data;
+        match self.info.get("dashmpd") {
+            Some(url) => Some(url),
+            None if !streaming_data.dash_manifest_url.is_empty() => {
+                Some(&streaming_data.dash_manifest_url)
+            }
+            None => None,
+        }
+    }
+
+    /// Fetch the HLS master playlist and list its variants, from the highest bandwidth.
+    pub fn hls_variants(&self) -> Result<Vec<Variant>> {
+        let url = self
+            .hls_manifest_url()
+            .ok_or_else(|| err_msg("This video has no HLS manifest, is it live ?"))?;
+        hls::fetch_variants(&self.client, url)
+    }
+
+    pub fn caption_tracks(&self) -> &[CaptionTrack] {
+        &self
+            .player_response
+            .captions
+            .player_captions_tracklist_renderer
+            .caption_tracks
+    }
+
+    /// Find a source by its itag, looking at the muxed sources first.
+    pub fn find_source(&self, itag: &str) -> Option<&VideoInfo> {
+        self.sources
+            .iter()
+            .chain(self.adaptive_sources.iter())
+            .find(|source| source.get("itag").map(String::as_str) == Some(itag))
+    }
+
+    /// The audio-only source with the highest bitrate.
+    pub fn best_audio(&self) -> Option<&VideoInfo> {
+        best_audio(&self.adaptive_sources)
+    }
+
+    /// Stream the source with the given itag as it downloads.
+    pub fn download_stream(&self, itag: &str) -> BoxStream<Chunk> {
+        match self.find_source(itag).and_then(source_url) {
+            Some(url) => {
+                debug!("Downloading itag {} from {}", itag, url);
+                self.client.get_stream(&url)
+            }
+            None => {
+                let error = format_err!("There is no source with itag {}", itag);
+                Box::new(stream::once(Err(error)))
+            }
+        }
+    }
+
+    /// Download the source with the given itag into `writer`, returns the number of bytes written.
+    pub fn download<W: Write>(&self, itag: &str, writer: &mut W) -> Result<u64> {
+        blocking::copy_stream(self.download_stream(itag), writer)
+    }
+
+    /// Download a caption track, `format` is one of the timedtext formats (`vtt`, `srv3`, ..)
+    /// and when missing YouTube sends its default XML.
+    pub fn download_caption(&self, track: &CaptionTrack, format: Option<&str>) -> Result<String> {
+        let url = match format {
+            Some(format) => format!("{}&fmt={}", track.base_url, format),
+            None => track.base_url.clone(),
+        };
+        self.client.get_string(&url)
+    }
+
     pub fn video_config(&self) -> Option<&VideoConfig> {
         if self.initialized {
             Some(&self.config)
@@ -127,82 +276,389 @@ impl Video {
         }
     }
 
+    /// Blocking version of `initialize_async`.
     #[inline]
     pub fn initialize(&mut self) -> Result<()> {
         if self.initialized {
             warn!("Video is already initialized !");
             return Ok(());
         }
+        // keep an uninitialized copy in place in case it fails.
+        let uninitialized = Video {
+            client: self.client.clone(),
+            ..Video::new(&self.id)
+        };
+        let video = mem::replace(self, uninitialized);
+        *self = blocking::block_on(video.initialize_async())?;
+        Ok(())
+    }
+
+    /// Fetch the video info, its player config and decipher its sources without blocking,
+    /// resolves to the initialized video.
+    pub fn initialize_async(self) -> BoxFuture<Video> {
+        if self.initialized {
+            warn!("Video is already initialized !");
+            return Box::new(future::ok(self));
+        }
         // We need to get it
-        self.get_video_info()?;
-        let url: String = format!("{}/watch?v={}", YOUTUBE_BASE_URL, self.id);
-        let mut res: Response = reqwest::get(&url)?;
-        let mut page = String::new();
-        res.read_to_string(&mut page)?;
-        let json_str = between(&page, "ytplayer.config = ", "</script>")
-            .ok_or_else(|| err_msg("Json String Not found"))?;
-        let config_pos = json_str
-            .rfind(";ytplayer.load")
-            .ok_or_else(|| err_msg("Config Postion Not found"))?;
-        let config_str = json_str
-            .get(0..config_pos)
-            .ok_or_else(|| err_msg("Config String Not found"))?;
-        self.config = serde_json::from_str(config_str)?;
-        let tokens = get_tokens(&self.config.assets.js)?;
-        for source in &mut self.sources {
+        let client = self.client.clone();
+        let id = self.id.clone();
+        let fetched = fetch_video_info(&self.client, &self.id, &[]).then(
+            move |result| -> BoxFuture<(FetchedInfo, VideoConfig, InfoPath)> {
+                match result {
+                    Ok(fetched) => {
+                        let config = watch_page_config(&client, &id)
+                            .map(|config| (fetched, config, InfoPath::Watch));
+                        Box::new(config)
+                    }
+                    Err(error) => match error.downcast::<VideoError>() {
+                        Ok(VideoError::AgeRestricted { reason }) => {
+                            warn!(
+                                "Video {} is age restricted ({}), trying the embedded player",
+                                id, reason
+                            );
+                            embedded_fallback(&client, &id)
+                        }
+                        Ok(error) => Box::new(future::err(error.into())),
+                        Err(error) => Box::new(future::err(error)),
+                    },
+                }
+            },
+        );
+        let initialized = fetched
+            .and_then(move |(fetched, config, info_path)| {
+                let mut video = self;
+                video.apply_info(fetched)?;
+                video.config = config;
+                video.config.info_path = info_path;
+                debug!("Video {} info obtained through {:?}", video.id, info_path);
+                Ok(video)
+            })
+            .and_then(|video| {
+                get_tokens_async(&video.client, &video.config.assets.js)
+                    .map(move |tokens| (video, tokens))
+            })
+            .and_then(|(mut video, tokens)| -> BoxFuture<Video> {
+                if let Err(error) = video.decipher_sources(&tokens) {
+                    return Box::new(future::err(error));
+                }
+                let manifest_url = match video.dash_manifest_url() {
+                    Some(manifest_url) => decipher_manifest_url(&tokens, manifest_url),
+                    None => return Box::new(future::ok(video)),
+                };
+                let manifest_url = match manifest_url {
+                    Ok(manifest_url) => manifest_url,
+                    Err(error) => return Box::new(future::err(error)),
+                };
+                // the manifest is only an extra, the video is still usable without it.
+                let merged =
+                    dash::fetch_formats_async(&video.client, &manifest_url).then(move |result| {
+                        match result {
+                            Ok(formats) => video.merge_adaptive_sources(formats),
+                            Err(error) => warn!("Error while loading the DASH manifest: {}", error),
+                        }
+                        Ok(video)
+                    });
+                Box::new(merged)
+            })
+            .map(|mut video| {
+                video.initialized = true;
+                info!("Video {} initialized successfully", video.id);
+                video
+            });
+        Box::new(initialized)
+    }
+
+    fn decipher_sources(&mut self, tokens: &[(String, usize)]) -> Result<()> {
+        for source in self
+            .sources
+            .iter_mut()
+            .chain(self.adaptive_sources.iter_mut())
+        {
             let signature;
             {
                 let s = source.entry("s".to_string()).or_insert_with(String::new);
                 if s == &"".to_string() {
                     continue;
                 }
-                signature = decipher(&tokens, s)?;
+                signature = decipher(tokens, s)?;
             }
             source.insert("signature".to_string(), signature);
         }
-        self.initialized = true;
-        info!("Video initialized successfully");
         Ok(())
     }
 
-    #[inline]
-    fn get_video_info(&mut self) -> Result<()> {
-        let url: String = format!("{}?video_id={}", YOUTUBE_INFO_URL, self.id);
-        let mut res: Response = reqwest::get(&url)?;
-        let mut data = Vec::new();
-        res.read_to_end(&mut data)?;
-        self.info = parse(&data).into_owned().collect();
-        let status = self
-            .info
+    // Add the formats we don't already know about, the known ones carry their signatures.
+    fn merge_adaptive_sources(&mut self, formats: VideoSorces) {
+        for format in formats {
+            let known = match format.get("itag") {
+                Some(itag) => self.find_source(itag).is_some(),
+                None => true,
+            };
+            if !known {
+                self.adaptive_sources.push(format);
+            }
+        }
+    }
+
+    fn apply_info(&mut self, fetched: FetchedInfo) -> Result<()> {
+        self.info = fetched.info;
+        self.player_response = fetched.player_response;
+        match self.info.get("url_encoded_fmt_stream_map") {
+            Some(sources) => self.sources = parse_sources(sources),
+            None if self.is_live() => debug!("Video {} is live, only HLS is available", self.id),
+            None => Err(err_msg("url_encoded_fmt_stream_map not found"))?,
+        }
+        if let Some(adaptive) = self.info.get("adaptive_fmts") {
+            self.adaptive_sources = parse_sources(adaptive);
+        }
+        Ok(())
+    }
+}
+
+// The parsed response of get_video_info.
+struct FetchedInfo {
+    info: VideoInfo,
+    player_response: PlayerResponse,
+}
+
+#[inline]
+fn fetch_video_info(
+    client: &HttpClient,
+    id: &str,
+    params: &[(&str, &str)],
+) -> BoxFuture<FetchedInfo> {
+    let query = Serializer::new(String::new())
+        .append_pair("video_id", id)
+        .extend_pairs(params)
+        .finish();
+    let url: String = format!("{}?{}", YOUTUBE_INFO_URL, query);
+    let fetched = client.get_bytes_async(&url).and_then(|data| {
+        let info: VideoInfo = parse(&data).into_owned().collect();
+        let player_response = match info.get("player_response") {
+            Some(player_response) => serde_json::from_str(player_response)?,
+            None => PlayerResponse::default(),
+        };
+        if let Some(error) = unplayable(&info, &player_response) {
+            Err(error)?
+        }
+        let status = info
             .get("status")
             .ok_or_else(|| err_msg("Cannot get Status"))?;
         debug!("Video Status {}", status);
-        if status == &"fail".to_string() {
-            Err(format_err!(
-                "Video Status {}, Maybe a bad ID ? or video not found!",
-                status
-            ))?
+        Ok(FetchedInfo {
+            info,
+            player_response,
+        })
+    });
+    Box::new(fetched)
+}
+
+#[inline]
+fn watch_page_config(client: &HttpClient, id: &str) -> BoxFuture<VideoConfig> {
+    let url: String = format!("{}/watch?v={}", YOUTUBE_BASE_URL, id);
+    let config = client.get_string_async(&url).and_then(|page| {
+        let json_str = between(&page, "ytplayer.config = ", "</script>")
+            .ok_or_else(|| err_msg("Json String Not found"))?;
+        let config_pos = json_str
+            .rfind(";ytplayer.load")
+            .ok_or_else(|| err_msg("Config Postion Not found"))?;
+        let config_str = json_str
+            .get(0..config_pos)
+            .ok_or_else(|| err_msg("Config String Not found"))?;
+        Ok(serde_json::from_str(config_str)?)
+    });
+    Box::new(config)
+}
+
+/// Age restricted videos can't be fetched like the others, but the embedded
+/// player can still get their info when we pass it the embed page `sts` and
+/// an `eurl` referer, so try that with the embedded and the detail page contexts.
+fn embedded_fallback(
+    client: &HttpClient,
+    id: &str,
+) -> BoxFuture<(FetchedInfo, VideoConfig, InfoPath)> {
+    let url: String = format!("{}/embed/{}", YOUTUBE_BASE_URL, id);
+    let client = client.clone();
+    let id = id.to_string();
+    let fallback = client
+        .get_string_async(&url)
+        .and_then(|page| {
+            let config_str = json_object(&page, "'PLAYER_CONFIG': ")
+                .ok_or_else(|| err_msg("Embedded Player Config Not found"))?;
+            let config: VideoConfig = serde_json::from_str(config_str)?;
+            Ok(config)
+        })
+        .and_then(move |config| {
+            let sts = config.sts.to_string();
+            let eurl = format!("{}{}", YOUTUBE_EMBED_REFERER, id);
+            let with_context = move |el: &str| {
+                fetch_video_info(&client, &id, &[("eurl", &eurl), ("sts", &sts), ("el", el)])
+            };
+            with_context("embedded")
+                .map(|fetched| (fetched, InfoPath::Embedded))
+                .or_else(move |error| {
+                    warn!("Embedded fallback with el=embedded failed: {}", error);
+                    with_context("detailpage")
+                        .map(|fetched| (fetched, InfoPath::EmbeddedDetailPage))
+                })
+                .map_err(|error| {
+                    warn!("Embedded fallback with el=detailpage failed: {}", error);
+                    let reason = match error.downcast_ref::<VideoError>() {
+                        Some(VideoError::AgeRestricted { reason }) => reason.clone(),
+                        _ => format!("{}", error),
+                    };
+                    VideoError::AgeRestricted { reason }.into()
+                })
+                .map(move |(fetched, path)| (fetched, config, path))
+        });
+    Box::new(fallback)
+}
+
+// Why the video info was refused, from the playability status of the player
+// response or else from the `status` of the info.
+fn unplayable(info: &VideoInfo, player_response: &PlayerResponse) -> Option<VideoError> {
+    if let Some(reason) = age_gate_reason(info, player_response) {
+        return Some(VideoError::AgeRestricted { reason });
+    }
+    let playability = &player_response.playability_status;
+    let reason = match playability.status.as_str() {
+        "LOGIN_REQUIRED" | "UNPLAYABLE" | "ERROR" => iter::once(&playability.reason)
+            .chain(&playability.messages)
+            .filter(|message| !message.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ if info.get("status").map(String::as_str) == Some("fail") => {
+            info.get("reason").cloned().unwrap_or_default()
         }
-        let sources = self
-            .info
-            .get("url_encoded_fmt_stream_map")
-            .ok_or_else(|| err_msg("url_encoded_fmt_stream_map not found"))?;
-        let sources = sources.split(',');
-        for (i, source) in sources.enumerate() {
-            let parsed = parse(&source.as_bytes());
-            let mut element = HashMap::new();
-            for (k, v) in parsed {
-                element.insert(k.to_string(), v.to_string());
-            }
-            self.sources.insert(i, element);
+        _ => return None,
+    };
+    let reason = if reason.is_empty() {
+        "Maybe a bad ID ? or video not found!".to_string()
+    } else {
+        reason
+    };
+    // once the age gate is ruled out, only the private videos want a login.
+    if playability.status == "LOGIN_REQUIRED" || reason.to_lowercase().contains("private") {
+        Some(VideoError::Private { reason })
+    } else {
+        Some(VideoError::Unavailable { reason })
+    }
+}
+
+// Check whether the video info was refused because of an age gate.
+fn age_gate_reason(info: &VideoInfo, player_response: &PlayerResponse) -> Option<String> {
+    let playability = &player_response.playability_status;
+    // private and members only videos want a login too, they are not age gated.
+    if playability.status == "LOGIN_REQUIRED"
+        && (playability.desktop_legacy_age_gate_reason != 0
+            || is_age_gate_reason(&playability.reason))
+    {
+        return Some(playability.reason.clone());
+    }
+    if info.get("status").map(String::as_str) != Some("fail") {
+        return None;
+    }
+    let reason = info.get("reason")?;
+    if is_age_gate_reason(reason) {
+        Some(reason.clone())
+    } else {
+        None
+    }
+}
+
+fn is_age_gate_reason(reason: &str) -> bool {
+    let lowercase = reason.to_lowercase();
+    AGE_GATE_REASONS.iter().any(|r| lowercase.contains(r))
+}
+
+/// The hits and misses of the player tokens cache since the start.
+pub fn token_cache_stats() -> (usize, usize) {
+    (
+        TOKEN_CACHE_HITS.load(Ordering::Relaxed),
+        TOKEN_CACHE_MISSES.load(Ordering::Relaxed),
+    )
+}
+
+/// The audio-only source with the highest bitrate among `sources`.
+pub fn best_audio(sources: &VideoSorces) -> Option<&VideoInfo> {
+    sources
+        .iter()
+        .filter(|source| source.get("type").is_some_and(|t| t.starts_with("audio/")))
+        .max_by_key(|source| {
+            source
+                .get("bitrate")
+                .and_then(|b| b.parse::<u64>().ok())
+                .unwrap_or(0)
+        })
+}
+
+/// The file extension for the mime type of a source, like `video/webm; codecs="vp9"`.
+pub fn mime_extension(mime: &str) -> &'static str {
+    match mime.split(';').next().unwrap_or_default().trim() {
+        "audio/mp4" => "m4a",
+        "audio/webm" | "video/webm" => "webm",
+        "video/3gpp" => "3gp",
+        "video/x-flv" => "flv",
+        _ => "mp4",
+    }
+}
+
+/// Build the final download url of a source, appending its deciphered signature if any.
+pub fn source_url(source: &VideoInfo) -> Option<String> {
+    let url = source.get("url")?;
+    match source.get("signature") {
+        Some(signature) => {
+            let param = source.get("sp").map_or("signature", String::as_str);
+            Some(format!("{}&{}={}", url, param, signature))
         }
-        Ok(())
+        None => Some(url.to_string()),
     }
 }
 
+// Parse a comma separated list of url encoded sources.
+fn parse_sources(sources: &str) -> VideoSorces {
+    sources
+        .split(',')
+        .filter(|source| !source.is_empty())
+        .map(|source| parse(source.as_bytes()).into_owned().collect())
+        .collect()
+}
+
 /// Extract signature deciphering tokens from html5player file.
 #[inline]
-fn get_tokens(html5_player_url: &str) -> Result<Vec<(String, usize)>> {
+fn get_tokens_async(
+    client: &HttpClient,
+    html5_player_url: &str,
+) -> BoxFuture<Vec<(String, usize)>> {
+    let player_id = match player_id(html5_player_url) {
+        Ok(player_id) => player_id,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    debug!("Player Id {:?}", player_id);
+    {
+        let container = TOKENSCONTAINER.lock().unwrap();
+        if let Some(cached_tokens) = container.get(&player_id) {
+            debug!("Found Cached Tokens for player {}", player_id);
+            TOKEN_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Box::new(future::ok(cached_tokens.to_vec()));
+        }
+    }
+    TOKEN_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    // get the file and Calculate the tokens
+    let player_url = YOUTUBE_BASE_URL.to_string() + html5_player_url;
+    let tokens = client.get_string_async(&player_url).and_then(move |file| {
+        let tokens = exteract_actions(&file)?;
+        debug!("Tokens {:?}", tokens);
+        let mut container = TOKENSCONTAINER.lock().unwrap();
+        container.insert(player_id, tokens.clone());
+        Ok(tokens)
+    });
+    Box::new(tokens)
+}
+
+fn player_id(html5_player_url: &str) -> Result<String> {
     let re = Regex::new(r"player[-_]([a-zA-Z0-9\-_]+)")?;
     let player_id = re
         .captures(html5_player_url)
@@ -210,23 +666,21 @@ fn get_tokens(html5_player_url: &str) -> Result<Vec<(String, usize)>> {
         .get(1)
         .ok_or_else(|| err_msg("There is No Player id"))?
         .as_str();
-    let mut container = TOKENSCONTAINER.lock().unwrap();
-    debug!("Player Id {:?}", player_id);
-    {
-        if let Some(cached_tokens) = container.get(player_id) {
-            debug!("Found Cached Tokens for player {}", player_id);
-            return Ok(cached_tokens.to_vec());
+    Ok(player_id.to_string())
+}
+
+/// The DASH manifest url carries its own ciphered signature as `/s/{signature}`.
+fn decipher_manifest_url(tokens: &[(String, usize)], manifest_url: &str) -> Result<String> {
+    match DASH_SIGNATURE_REGEX.captures(manifest_url) {
+        Some(captures) => {
+            let signature = decipher(tokens, &captures[1])?;
+            let deciphered = format!("/signature/{}", signature);
+            Ok(DASH_SIGNATURE_REGEX
+                .replace(manifest_url, deciphered.as_str())
+                .into_owned())
         }
+        None => Ok(manifest_url.to_string()),
     }
-    // get the file and Calculate the tokens
-    let player_url = YOUTUBE_BASE_URL.to_string() + html5_player_url;
-    let mut res: Response = reqwest::get(&player_url)?;
-    let mut file = String::new();
-    res.read_to_string(&mut file)?;
-    let tokens = exteract_actions(&file)?;
-    debug!("Tokens {:?}", tokens);
-    container.insert(player_id.to_string(), tokens.clone());
-    Ok(tokens)
 }
 
 /// Decipher a signature based on action tokens.
@@ -341,9 +795,109 @@ fn multi_regex<'t>(regex: &'static Regex, text: &'t str) -> Result<&'t str> {
     }
 }
 
+// Get the json object that starts right after left in haystack
+fn json_object<'a>(haystack: &'a str, left: &str) -> Option<&'a str> {
+    let from = haystack.find(left)? + left.len();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in haystack[from..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '{' if !in_string => depth += 1,
+            '}' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    return haystack.get(from..=from + i);
+                }
+            }
+            _ if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
 // Get the value between left and righ from haystack
 fn between<'a>(haystack: &'a str, left: &str, right: &str) -> Option<&'a str> {
     let from = haystack.find(left)? + left.len();
     let to = haystack.rfind(right)?;
     haystack.get(from..to)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unplayable_with(status: &str, playability: &str) -> Option<VideoError> {
+        let info: VideoInfo = iter::once(("status".to_string(), status.to_string())).collect();
+        let player_response =
+            serde_json::from_str(&format!(r#"{{"playabilityStatus": {}}}"#, playability)).unwrap();
+        unplayable(&info, &player_response)
+    }
+
+    #[test]
+    fn tells_why_a_video_is_unplayable() {
+        assert!(unplayable_with("ok", r#"{"status": "OK"}"#).is_none());
+        match unplayable_with(
+            "fail",
+            r#"{"status": "LOGIN_REQUIRED", "reason": "Sign in to confirm your age",
+                "desktopLegacyAgeGateReason": 1}"#,
+        ) {
+            Some(VideoError::AgeRestricted { reason }) => {
+                assert_eq!(reason, "Sign in to confirm your age")
+            }
+            other => panic!("{:?}", other),
+        }
+        match unplayable_with(
+            "fail",
+            r#"{"status": "LOGIN_REQUIRED", "reason": "Video unavailable",
+                "messages": ["This is a private video. Please sign in to verify that you may see it."]}"#,
+        ) {
+            Some(VideoError::Private { reason }) => assert!(reason.contains("private video")),
+            other => panic!("{:?}", other),
+        }
+        match unplayable_with("ok", r#"{"status": "LOGIN_REQUIRED"}"#) {
+            Some(VideoError::Private { .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        match unplayable_with(
+            "fail",
+            r#"{"status": "UNPLAYABLE", "reason": "Video unavailable",
+                "messages": ["The uploader has not made this video available in your country."]}"#,
+        ) {
+            Some(VideoError::Unavailable { reason }) => assert_eq!(
+                reason,
+                "Video unavailable The uploader has not made this video available in your country."
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_info_status() {
+        let mut info: VideoInfo = iter::once(("status".to_string(), "fail".to_string())).collect();
+        let player_response = PlayerResponse::default();
+        match unplayable(&info, &player_response) {
+            Some(VideoError::Unavailable { reason }) => {
+                assert_eq!(reason, "Maybe a bad ID ? or video not found!")
+            }
+            other => panic!("{:?}", other),
+        }
+        info.insert("reason".to_string(), "This video is private.".to_string());
+        match unplayable(&info, &player_response) {
+            Some(VideoError::Private { .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        info.insert(
+            "reason".to_string(),
+            "This video may be inappropriate for some users.".to_string(),
+        );
+        match unplayable(&info, &player_response) {
+            Some(VideoError::AgeRestricted { .. }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
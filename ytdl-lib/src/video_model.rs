@@ -1,4 +1,4 @@
-use serde_derive::{Serialize, Deserialize};
+use serde_derive::{Deserialize, Serialize};
 #[serde(default)]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 pub struct Assets {
@@ -13,4 +13,81 @@ pub struct VideoConfig {
     pub html5: bool,
     pub sts: i64,
     pub url: String,
+    /// How the video info was obtained, this is not part of the player config.
+    #[serde(skip_deserializing)]
+    pub info_path: InfoPath,
+}
+
+/// The flow that `get_video_info` succeeded with.
+#[serde(rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub enum InfoPath {
+    /// The regular watch page.
+    #[default]
+    Watch,
+    /// The embedded player, used for age restricted videos.
+    Embedded,
+    /// The embedded player `sts` with the detail page client context.
+    EmbeddedDetailPage,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct PlayerResponse {
+    pub captions: Captions,
+    pub playability_status: PlayabilityStatus,
+    pub streaming_data: StreamingData,
+    pub video_details: VideoDetails,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct StreamingData {
+    pub hls_manifest_url: String,
+    pub dash_manifest_url: String,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct VideoDetails {
+    pub is_live: bool,
+    pub is_live_content: bool,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct PlayabilityStatus {
+    pub status: String,
+    pub reason: String,
+    /// Set for the age gated videos, `LOGIN_REQUIRED` also covers the private ones.
+    pub desktop_legacy_age_gate_reason: i64,
+    /// More about the reason, like whether the video is private.
+    pub messages: Vec<String>,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct Captions {
+    pub player_captions_tracklist_renderer: CaptionTracklist,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct CaptionTracklist {
+    pub caption_tracks: Vec<CaptionTrack>,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct CaptionTrack {
+    pub base_url: String,
+    pub name: CaptionName,
+    pub language_code: String,
+    pub kind: String,
+}
+
+#[serde(default, rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct CaptionName {
+    pub simple_text: String,
 }
@@ -0,0 +1,29 @@
+#!/bin/sh
+# Stands in for ffmpeg in the tests, the end of the input url picks what it does.
+args="$*"
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-i" ]; then
+        input=$2
+    fi
+    shift
+done
+case "$input" in
+    *args) printf '%s' "$args" ;;
+    *fail)
+        echo "[https @ 0x1] HTTP error 403 Forbidden" >&2
+        echo "https://r1.googlevideo.com/videoplayback: Server returned 403 Forbidden (access denied)" >&2
+        exit 1
+        ;;
+    *progress)
+        echo "out_time_us=500000" >&2
+        echo "progress=end" >&2
+        printf 'GIF89a'
+        ;;
+    *hang) exec sleep 10 ;;
+    *stall)
+        printf 'ID3'
+        exec sleep 10
+        ;;
+    *large) exec head -c 1000000 /dev/zero ;;
+    *) printf 'frame' ;;
+esac
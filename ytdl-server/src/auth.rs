@@ -0,0 +1,344 @@
+use crate::error::{error_response, ErrorCode};
+use crate::routes::Route;
+use failure::{bail, format_err, Error, Fail};
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{Body, Request, Response};
+use log::debug;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const API_KEY_HEADER: &str = "X-Api-Key";
+const API_KEY_PARAM: &str = "api_key";
+
+/// A key of the keys file, the missing limits are unlimited.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    /// Who the key was given to, used in the logs.
+    #[serde(default)]
+    pub name: String,
+    pub requests_per_minute: Option<u32>,
+    /// The total duration of the extracted clips, in seconds.
+    pub gif_seconds_per_day: Option<u64>,
+    /// Like `/watch` or `/jobs`, which also allows `/jobs/{id}`. Every endpoint when missing.
+    pub endpoints: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct KeysFile {
+    keys: Vec<ApiKey>,
+}
+
+/// Who made a request, added to the request extensions once the key is checked.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub key: String,
+    pub name: String,
+}
+
+/// Why a request was refused, turned into a 401, 403 or 429 response.
+#[derive(Debug)]
+pub struct AuthError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// In seconds, for 429.
+    pub retry_after: Option<u64>,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Fail for AuthError {}
+
+impl AuthError {
+    fn new(code: ErrorCode, message: &str) -> Self {
+        AuthError {
+            code,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        let mut response = error_response(self.code, &self.message);
+        if let Some(seconds) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    minute: u64,
+    requests: u32,
+    day: u64,
+    clip_seconds: u64,
+}
+
+/// The API keys and what they used so far, everything is allowed without keys.
+#[derive(Debug, Default)]
+pub struct Auth {
+    keys: HashMap<String, ApiKey>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Auth {
+    /// Load the keys from a JSON file like
+    /// `{"keys": [{"key": "...", "name": "frontend", "requests_per_minute": 60}]}`.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format_err!("Cannot read the API keys file {}: {}", path, e))?;
+        let file: KeysFile = serde_json::from_str(&content)
+            .map_err(|e| format_err!("Bad API keys file {}: {}", path, e))?;
+        // an empty list would silently leave the server open.
+        if file.keys.is_empty() {
+            bail!(
+                "The API keys file {} has no keys, unset auth.keys_file to run without keys",
+                path
+            );
+        }
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|key| (key.key.clone(), key))
+            .collect();
+        Ok(Auth {
+            keys,
+            usage: Mutex::default(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Check the key of a request against its `route` and its rate, then remember
+    /// who made it in the request extensions. The public routes need no key.
+    pub fn authorize(
+        &self,
+        req: &mut Request<Body>,
+        route: Option<&Route>,
+    ) -> std::result::Result<(), AuthError> {
+        if !self.is_enabled() || route.is_some_and(|route| route.public) {
+            return Ok(());
+        }
+        let key = request_key(req).ok_or_else(|| {
+            AuthError::new(
+                ErrorCode::Unauthorized,
+                "Missing API key, send it in the X-Api-Key header or the api_key param",
+            )
+        })?;
+        let api_key = self
+            .keys
+            .get(&key)
+            .ok_or_else(|| AuthError::new(ErrorCode::Unauthorized, "Unknown API key"))?;
+        if let Some(endpoints) = &api_key.endpoints {
+            let allowed = route.is_some_and(|route| {
+                endpoints
+                    .iter()
+                    .any(|endpoint| endpoint_allows(endpoint, route.path))
+            });
+            if !allowed {
+                let message = format!("This API key can't use {}", req.uri().path());
+                return Err(AuthError::new(ErrorCode::Forbidden, &message));
+            }
+        }
+        if let Some(limit) = api_key.requests_per_minute {
+            let now = unix_seconds();
+            let mut usage = self.usage.lock().unwrap();
+            let usage = usage.entry(key.clone()).or_default();
+            if usage.minute != now / 60 {
+                usage.minute = now / 60;
+                usage.requests = 0;
+            }
+            if usage.requests >= limit {
+                return Err(AuthError {
+                    code: ErrorCode::RateLimited,
+                    message: format!("Too many requests, this API key allows {} a minute", limit),
+                    retry_after: Some(60 - now % 60),
+                });
+            }
+            usage.requests += 1;
+        }
+        let caller = Caller {
+            key,
+            name: api_key.name.clone(),
+        };
+        req.extensions_mut().insert(caller);
+        Ok(())
+    }
+
+    /// Count `seconds` of extracted clip against the daily quota of the caller.
+    pub fn charge_clip(
+        &self,
+        caller: Option<&Caller>,
+        seconds: u64,
+    ) -> std::result::Result<(), AuthError> {
+        let caller = match caller {
+            Some(caller) => caller,
+            None => return Ok(()),
+        };
+        let limit = match self
+            .keys
+            .get(&caller.key)
+            .and_then(|k| k.gif_seconds_per_day)
+        {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = unix_seconds();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(caller.key.clone()).or_default();
+        if usage.day != now / 86400 {
+            usage.day = now / 86400;
+            usage.clip_seconds = 0;
+        }
+        if usage.clip_seconds + seconds > limit {
+            return Err(AuthError {
+                code: ErrorCode::QuotaExceeded,
+                message: format!(
+                    "Daily quota exceeded, this API key allows {} seconds of clips a day \
+                     and {} are left",
+                    limit,
+                    limit.saturating_sub(usage.clip_seconds)
+                ),
+                retry_after: Some(86400 - now % 86400),
+            });
+        }
+        usage.clip_seconds += seconds;
+        debug!(
+            "{} used {} of its {} clip seconds today",
+            caller.name, usage.clip_seconds, limit
+        );
+        Ok(())
+    }
+
+    /// Give back what `charge_clip` took, for the clips we could not make.
+    pub fn refund_clip(&self, caller: Option<&Caller>, seconds: u64) {
+        if let Some(caller) = caller {
+            if let Some(usage) = self.usage.lock().unwrap().get_mut(&caller.key) {
+                usage.clip_seconds = usage.clip_seconds.saturating_sub(seconds);
+            }
+        }
+    }
+}
+
+fn request_key(req: &Request<Body>) -> Option<String> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER) {
+        return key.to_str().ok().map(str::to_string);
+    }
+    let query = req.uri().query()?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == API_KEY_PARAM)
+        .map(|(_, key)| key.into_owned())
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Whether an `endpoints` entry of a key covers the route at `path`, like
+// `/jobs` covers `/jobs/{id}`. The routes are matched rather than the request
+// path, so `/` is only the help.
+fn endpoint_allows(endpoint: &str, path: &str) -> bool {
+    let endpoint = match endpoint.trim_end_matches('/') {
+        "" => "/",
+        endpoint => endpoint,
+    };
+    path == endpoint
+        || path
+            .strip_prefix(endpoint)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::routes::{find, routes};
+    use hyper::Method;
+    use std::env;
+    use std::path::PathBuf;
+
+    fn keys_file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ytdl-{}-{}.json", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn an_empty_keys_file_is_refused() {
+        let path = keys_file("empty-keys", r#"{"keys": []}"#);
+        let error = Auth::from_file(path.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("has no keys"), "{}", error);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keys_only_reach_their_endpoints() {
+        let path = keys_file(
+            "endpoint-keys",
+            r#"{"keys": [{"key": "k", "endpoints": ["/", "/jobs/"]}]}"#,
+        );
+        let auth = Auth::from_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        let routes = routes(&Config::default());
+        let authorize = |method: Method, path: &str| {
+            let mut req = Request::builder()
+                .method(method.clone())
+                .uri(path)
+                .header(API_KEY_HEADER, "k")
+                .body(Body::empty())
+                .unwrap();
+            auth.authorize(&mut req, find(&routes, &method, path))
+                .map_err(|denied| denied.code)
+        };
+        assert_eq!(authorize(Method::POST, "/jobs"), Ok(()));
+        assert_eq!(authorize(Method::GET, "/jobs/abc/result"), Ok(()));
+        assert_eq!(authorize(Method::GET, "/watch"), Err(ErrorCode::Forbidden));
+        assert_eq!(
+            authorize(Method::GET, "/jobsfoo"),
+            Err(ErrorCode::Forbidden)
+        );
+        assert_eq!(
+            authorize(Method::GET, "/nowhere"),
+            Err(ErrorCode::Forbidden)
+        );
+    }
+
+    #[test]
+    fn refunds_give_the_quota_back() {
+        let path = keys_file(
+            "quota-keys",
+            r#"{"keys": [{"key": "k", "name": "test", "gif_seconds_per_day": 10}]}"#,
+        );
+        let auth = Auth::from_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(auth.is_enabled());
+        let caller = Caller {
+            key: "k".to_string(),
+            name: "test".to_string(),
+        };
+        auth.charge_clip(Some(&caller), 8).unwrap();
+        let denied = auth.charge_clip(Some(&caller), 5).unwrap_err();
+        assert_eq!(denied.code, ErrorCode::QuotaExceeded);
+        auth.refund_clip(Some(&caller), 8);
+        auth.charge_clip(Some(&caller), 10).unwrap();
+    }
+}
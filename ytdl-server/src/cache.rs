@@ -0,0 +1,219 @@
+use crate::error::ApiError;
+use crate::metrics::METRICS;
+use failure::{err_msg, Error};
+use futures::future::{self, Shared};
+use futures::Future;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+use ytdl_lib::{best_audio, Video, VideoInfo, VideoSorces};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+// drop the entries a bit before the urls really expire, so clients have time to use them.
+const EXPIRE_MARGIN: Duration = Duration::from_secs(5 * 60);
+// for the sources without an `expire` parameter.
+const DEFAULT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// What we keep of a resolved video.
+pub struct CachedVideo {
+    pub title: Option<String>,
+    pub sources: VideoSorces,
+    pub adaptive_sources: VideoSorces,
+}
+
+impl CachedVideo {
+    /// Find a format by its itag, in the regular then the adaptive sources.
+    pub fn find_source(&self, itag: &str) -> Option<&VideoInfo> {
+        self.sources
+            .iter()
+            .chain(self.adaptive_sources.iter())
+            .find(|source| source.get("itag").map(String::as_str) == Some(itag))
+    }
+
+    /// The audio-only source with the highest bitrate.
+    pub fn best_audio(&self) -> Option<&VideoInfo> {
+        best_audio(&self.adaptive_sources)
+    }
+}
+
+enum Entry {
+    Ready {
+        video: Arc<CachedVideo>,
+        expires: SystemTime,
+    },
+    // a resolve in flight, every request for the same video waits on it.
+    Pending(Shared<BoxFuture<Arc<CachedVideo>>>),
+}
+
+/// Resolved videos kept in memory by id, until their source urls expire.
+#[derive(Clone)]
+pub struct VideoCache {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    capacity: usize,
+}
+
+impl VideoCache {
+    pub fn new(capacity: usize) -> Self {
+        VideoCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Get a video, from the cache when its sources are still fresh,
+    /// otherwise from youtube, sharing one resolve between concurrent requests.
+    pub fn video(&self, video_id: &str) -> BoxFuture<Arc<CachedVideo>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(video_id) {
+            Some(Entry::Ready { video, expires }) if *expires > SystemTime::now() => {
+                debug!("Video {} found in the cache", video_id);
+                return Box::new(future::ok(video.clone()));
+            }
+            Some(Entry::Pending(resolve)) => {
+                debug!("Waiting on the pending resolve of {}", video_id);
+                return shared_result(resolve.clone());
+            }
+            _ => {}
+        }
+
+        let cache = self.clone();
+        let id = video_id.to_string();
+        let resolve: BoxFuture<Arc<CachedVideo>> = Box::new(
+            Video::new(video_id)
+                .initialize_async()
+                .and_then(|video| {
+                    let sources = video
+                        .video_sources()
+                        .ok_or_else(|| err_msg("Ops, Error While Getting Video Sources."))?;
+                    Ok(Arc::new(CachedVideo {
+                        title: video.title().map(str::to_string),
+                        sources: sources.clone(),
+                        adaptive_sources: video.adaptive_sources().cloned().unwrap_or_default(),
+                    }))
+                })
+                .then(move |result| {
+                    cache.finish(&id, &result);
+                    result
+                }),
+        );
+        let resolve = resolve.shared();
+        self.evict(&mut entries);
+        entries.insert(video_id.to_string(), Entry::Pending(resolve.clone()));
+        shared_result(resolve)
+    }
+
+    // Replace the pending entry with the result, failures are not cached.
+    fn finish(&self, video_id: &str, result: &Result<Arc<CachedVideo>, Error>) {
+        let mut entries = self.entries.lock().unwrap();
+        match result {
+            Ok(video) => {
+                let expires = expiry(video);
+                entries.insert(
+                    video_id.to_string(),
+                    Entry::Ready {
+                        video: video.clone(),
+                        expires,
+                    },
+                );
+            }
+            Err(error) => {
+                METRICS.upstream_error(error);
+                entries.remove(video_id);
+            }
+        }
+    }
+
+    // Make room for one more entry, dropping the expired ones first and then
+    // the ones closest to expiring.
+    fn evict(&self, entries: &mut HashMap<String, Entry>) {
+        let now = SystemTime::now();
+        entries.retain(|_, entry| match entry {
+            Entry::Ready { expires, .. } => *expires > now,
+            Entry::Pending(_) => true,
+        });
+        while entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .filter_map(|(id, entry)| match entry {
+                    Entry::Ready { expires, .. } => Some((id.clone(), *expires)),
+                    Entry::Pending(_) => None,
+                })
+                .min_by_key(|(_, expires)| *expires);
+            match oldest {
+                Some((id, _)) => {
+                    entries.remove(&id);
+                }
+                // only resolves in flight, let it grow until they are done.
+                None => break,
+            }
+        }
+    }
+}
+
+fn shared_result(resolve: Shared<BoxFuture<Arc<CachedVideo>>>) -> BoxFuture<Arc<CachedVideo>> {
+    let result = resolve
+        .map(|video| (*video).clone())
+        // keep what kind of error it was for the response.
+        .map_err(|error| ApiError::classify(&error).into());
+    Box::new(result)
+}
+
+// The video is usable until the first of its urls expires, the adaptive ones
+// included since /download and /audio serve them.
+fn expiry(video: &CachedVideo) -> SystemTime {
+    let now = SystemTime::now();
+    video
+        .sources
+        .iter()
+        .chain(video.adaptive_sources.iter())
+        .filter_map(|source| source.get("url"))
+        .filter_map(|url| Url::parse(url).ok())
+        .filter_map(|url| {
+            url.query_pairs()
+                .find(|(key, _)| key == "expire")
+                .and_then(|(_, value)| value.parse().ok())
+        })
+        .map(|expire| UNIX_EPOCH + Duration::from_secs(expire))
+        .min()
+        .map(|expires| expires - EXPIRE_MARGIN)
+        .unwrap_or(now + DEFAULT_TTL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(expire: u64) -> VideoInfo {
+        let url = format!("https://r1.googlevideo.com/videoplayback?expire={}", expire);
+        vec![("url".to_string(), url)].into_iter().collect()
+    }
+
+    #[test]
+    fn expiry_looks_at_the_adaptive_sources() {
+        let video = CachedVideo {
+            title: None,
+            sources: VideoSorces::new(),
+            adaptive_sources: vec![source(4_000_000_000), source(3_000_000_000)],
+        };
+        assert_eq!(
+            expiry(&video),
+            UNIX_EPOCH + Duration::from_secs(3_000_000_000) - EXPIRE_MARGIN
+        );
+    }
+
+    #[test]
+    fn expiry_takes_the_first_url_to_expire() {
+        let video = CachedVideo {
+            title: None,
+            sources: vec![source(3_500_000_000)],
+            adaptive_sources: vec![source(4_000_000_000)],
+        };
+        assert_eq!(
+            expiry(&video),
+            UNIX_EPOCH + Duration::from_secs(3_500_000_000) - EXPIRE_MARGIN
+        );
+    }
+}
@@ -0,0 +1,580 @@
+use failure::{bail, format_err, Error};
+use serde::de::{Deserialize, Deserializer, Error as _};
+use serde_derive::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+use ytdl_lib::clip::{ClipFormat, ClipOptions, FrameFormat};
+use ytdl_lib::ffmpeg::Limits;
+
+type Result<T> = std::result::Result<T, Error>;
+
+// read when CONFIG_FILE is not set and it exists.
+const DEFAULT_CONFIG_FILE: &str = "ytdl.toml";
+
+/// The settings of the server, read from the TOML file in `CONFIG_FILE` (or
+/// `ytdl.toml`) then overridden by the environment. Every setting has a default:
+///
+/// ```toml
+/// [server]
+/// bind = "0.0.0.0:8080"        # BIND_ADDRESS, or only the port with PORT
+///
+/// [ffmpeg]
+/// path = "ffmpeg"              # FFMPEG_PATH
+/// jobs = 4                     # FFMPEG_JOBS, the number of cpus by default
+/// timeout_seconds = 300        # FFMPEG_TIMEOUT, a run is killed after it
+/// idle_timeout_seconds = 30    # FFMPEG_IDLE_TIMEOUT, a stream writing nothing for it too
+/// threads = 2                  # FFMPEG_THREADS, 0 lets ffmpeg pick
+/// max_output_mb = 64           # FFMPEG_MAX_OUTPUT_MB, bigger clips and frames fail
+///
+/// [clips]
+/// max_seconds = 60             # CLIP_MAX_SECONDS, clips must be shorter
+/// min_width = 64              # CLIP_MIN_WIDTH
+/// max_width = 720              # CLIP_MAX_WIDTH
+/// max_fps = 30                 # CLIP_MAX_FPS
+/// width = 340                  # CLIP_WIDTH
+/// quality = 75                 # CLIP_QUALITY
+/// format = "gif"               # CLIP_FORMAT
+///
+/// [frames]
+/// min_width = 64               # FRAME_MIN_WIDTH
+/// max_width = 1920             # FRAME_MAX_WIDTH
+/// width = 640                  # FRAME_WIDTH
+/// format = "jpeg"              # FRAME_FORMAT
+///
+/// [jobs]
+/// max_queued = 64              # JOBS_MAX_QUEUED, waiting for an ffmpeg slot
+/// max_stored = 1000            # JOBS_MAX_STORED, with the finished ones
+/// max_results_mb = 512         # JOBS_MAX_RESULTS_MB, kept for an hour
+///
+/// [cache]
+/// watch_size = 1024            # WATCH_CACHE_SIZE
+///
+/// [upstream]
+/// allowed_hosts = ["googlevideo.com"]  # ALLOWED_HOSTS, comma separated
+/// allowed_paths = ["/videoplayback"]   # ALLOWED_PATHS, the url path starts with one
+///
+/// [auth]
+/// keys_file = "keys.json"      # API_KEYS_FILE, the server is open without it
+///
+/// [rate_limit]
+/// cheap_per_minute = 120       # RATE_LIMIT_CHEAP, 0 to disable
+/// expensive_per_minute = 10    # RATE_LIMIT_EXPENSIVE
+/// trusted_proxies = []         # TRUSTED_PROXIES, comma separated
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub ffmpeg: FfmpegConfig,
+    pub clips: ClipsConfig,
+    pub frames: FramesConfig,
+    pub jobs: JobsConfig,
+    pub cache: CacheConfig,
+    pub upstream: UpstreamConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: ([0, 0, 0, 0], 8080).into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FfmpegConfig {
+    pub path: String,
+    /// How many ffmpeg jobs run at once.
+    pub jobs: usize,
+    pub timeout_seconds: u64,
+    /// The streams last as long as the audio, only how long they write
+    /// nothing is bounded.
+    pub idle_timeout_seconds: u64,
+    /// The threads of one ffmpeg run.
+    pub threads: u32,
+    pub max_output_mb: u64,
+}
+
+impl Default for FfmpegConfig {
+    fn default() -> Self {
+        FfmpegConfig {
+            path: "ffmpeg".to_string(),
+            jobs: num_cpus::get(),
+            timeout_seconds: 300,
+            idle_timeout_seconds: 30,
+            threads: 2,
+            max_output_mb: 64,
+        }
+    }
+}
+
+impl FfmpegConfig {
+    /// The bounds of the runs making a clip or a frame.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            timeout: Some(Duration::from_secs(self.timeout_seconds)),
+            idle_timeout: None,
+            threads: Some(self.threads).filter(|&threads| threads > 0),
+            max_output: Some(self.max_output_mb * 1024 * 1024),
+        }
+    }
+
+    /// The bounds of the streamed runs, they only stop at the end of the input.
+    pub fn stream_limits(&self) -> Limits {
+        Limits {
+            timeout: None,
+            idle_timeout: Some(Duration::from_secs(self.idle_timeout_seconds)),
+            threads: Some(self.threads).filter(|&threads| threads > 0),
+            max_output: None,
+        }
+    }
+}
+
+/// The bounds and the defaults of `/extract` and the jobs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClipsConfig {
+    pub max_seconds: u64,
+    pub min_width: u32,
+    pub max_width: u32,
+    pub max_fps: u32,
+    pub width: u32,
+    pub quality: u8,
+    #[serde(deserialize_with = "clip_format")]
+    pub format: ClipFormat,
+}
+
+impl Default for ClipsConfig {
+    fn default() -> Self {
+        let options = ClipOptions::default();
+        ClipsConfig {
+            max_seconds: 60,
+            min_width: 64,
+            max_width: 720,
+            max_fps: 30,
+            width: options.width,
+            quality: options.quality,
+            format: options.format,
+        }
+    }
+}
+
+/// The bounds and the defaults of `/frame`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FramesConfig {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub width: u32,
+    #[serde(deserialize_with = "frame_format")]
+    pub format: FrameFormat,
+}
+
+impl Default for FramesConfig {
+    fn default() -> Self {
+        FramesConfig {
+            min_width: 64,
+            max_width: 1920,
+            width: 640,
+            format: FrameFormat::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// How many videos the `/watch` cache keeps.
+    pub watch_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { watch_size: 1024 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The hosts (and their subdomains) the video urls we get can point to.
+    pub allowed_hosts: Vec<String>,
+    /// The prefixes of their paths.
+    pub allowed_paths: Vec<String>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            allowed_hosts: vec!["googlevideo.com".to_string()],
+            allowed_paths: vec!["/videoplayback".to_string()],
+        }
+    }
+}
+
+/// The bounds of `POST /jobs`, the extra jobs are refused.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    pub max_queued: usize,
+    pub max_stored: usize,
+    pub max_results_mb: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            max_queued: 64,
+            max_stored: 1000,
+            max_results_mb: 512,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub keys_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub cheap_per_minute: u32,
+    pub expensive_per_minute: u32,
+    /// The proxies we take the `X-Forwarded-For` header from.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            cheap_per_minute: 120,
+            expensive_per_minute: 10,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, apply the environment and check the result.
+    pub fn load() -> Result<Self> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => Config::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format_err!("Cannot read the config file {}: {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format_err!("Bad config file {}: {}", path, e))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_parse("BIND_ADDRESS", &mut self.server.bind)?;
+        let mut port = self.server.bind.port();
+        env_parse("PORT", &mut port)?;
+        self.server.bind.set_port(port);
+        env_parse("FFMPEG_PATH", &mut self.ffmpeg.path)?;
+        env_parse("FFMPEG_JOBS", &mut self.ffmpeg.jobs)?;
+        env_parse("FFMPEG_TIMEOUT", &mut self.ffmpeg.timeout_seconds)?;
+        env_parse("FFMPEG_IDLE_TIMEOUT", &mut self.ffmpeg.idle_timeout_seconds)?;
+        env_parse("FFMPEG_THREADS", &mut self.ffmpeg.threads)?;
+        env_parse("FFMPEG_MAX_OUTPUT_MB", &mut self.ffmpeg.max_output_mb)?;
+        env_parse("CLIP_MAX_SECONDS", &mut self.clips.max_seconds)?;
+        env_parse("CLIP_MIN_WIDTH", &mut self.clips.min_width)?;
+        env_parse("CLIP_MAX_WIDTH", &mut self.clips.max_width)?;
+        env_parse("CLIP_MAX_FPS", &mut self.clips.max_fps)?;
+        env_parse("CLIP_WIDTH", &mut self.clips.width)?;
+        env_parse("CLIP_QUALITY", &mut self.clips.quality)?;
+        env_with("CLIP_FORMAT", &mut self.clips.format, ClipFormat::from)?;
+        env_parse("FRAME_MIN_WIDTH", &mut self.frames.min_width)?;
+        env_parse("FRAME_MAX_WIDTH", &mut self.frames.max_width)?;
+        env_parse("FRAME_WIDTH", &mut self.frames.width)?;
+        env_with("FRAME_FORMAT", &mut self.frames.format, FrameFormat::from)?;
+        env_parse("JOBS_MAX_QUEUED", &mut self.jobs.max_queued)?;
+        env_parse("JOBS_MAX_STORED", &mut self.jobs.max_stored)?;
+        env_parse("JOBS_MAX_RESULTS_MB", &mut self.jobs.max_results_mb)?;
+        env_parse("WATCH_CACHE_SIZE", &mut self.cache.watch_size)?;
+        env_list("ALLOWED_HOSTS", &mut self.upstream.allowed_hosts)?;
+        env_list("ALLOWED_PATHS", &mut self.upstream.allowed_paths)?;
+        if let Ok(path) = env::var("API_KEYS_FILE") {
+            self.auth.keys_file = Some(path);
+        }
+        env_parse("RATE_LIMIT_CHEAP", &mut self.rate_limit.cheap_per_minute)?;
+        env_parse(
+            "RATE_LIMIT_EXPENSIVE",
+            &mut self.rate_limit.expensive_per_minute,
+        )?;
+        env_list("TRUSTED_PROXIES", &mut self.rate_limit.trusted_proxies)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let clips = &self.clips;
+        if self.ffmpeg.jobs == 0 {
+            bail!("ffmpeg.jobs should be at least 1");
+        }
+        if self.ffmpeg.timeout_seconds == 0 || self.ffmpeg.idle_timeout_seconds == 0 {
+            bail!("ffmpeg.timeout_seconds and ffmpeg.idle_timeout_seconds should be at least 1");
+        }
+        if self.ffmpeg.max_output_mb == 0 {
+            bail!("ffmpeg.max_output_mb should be at least 1");
+        }
+        if clips.max_seconds == 0 {
+            bail!("clips.max_seconds should be at least 1");
+        }
+        check_width("clips", clips.min_width, clips.max_width, clips.width)?;
+        if clips.max_fps == 0 {
+            bail!("clips.max_fps should be at least 1");
+        }
+        if clips.quality == 0 || clips.quality > 100 {
+            bail!(
+                "clips.quality should be from 1 to 100, not {}",
+                clips.quality
+            );
+        }
+        let frames = &self.frames;
+        check_width("frames", frames.min_width, frames.max_width, frames.width)?;
+        if self.jobs.max_stored == 0 || self.jobs.max_results_mb == 0 {
+            bail!("jobs.max_stored and jobs.max_results_mb should be at least 1");
+        }
+        if self.cache.watch_size == 0 {
+            bail!("cache.watch_size should be at least 1");
+        }
+        if self.upstream.allowed_hosts.is_empty() {
+            bail!("upstream.allowed_hosts is empty, no video url would be accepted");
+        }
+        if let Some(host) = self
+            .upstream
+            .allowed_hosts
+            .iter()
+            .find(|host| host.is_empty() || host.contains('/'))
+        {
+            bail!(
+                "upstream.allowed_hosts should only have host names, not {:?}",
+                host
+            );
+        }
+        if self.upstream.allowed_paths.is_empty() {
+            bail!("upstream.allowed_paths is empty, no video url would be accepted");
+        }
+        if let Some(path) = self
+            .upstream
+            .allowed_paths
+            .iter()
+            .find(|path| !path.starts_with('/'))
+        {
+            bail!(
+                "upstream.allowed_paths should start with a '/', not {:?}",
+                path
+            );
+        }
+        check_tool("ffmpeg.path", &self.ffmpeg.path)?;
+        Ok(())
+    }
+}
+
+fn check_width(section: &str, min: u32, max: u32, default: u32) -> Result<()> {
+    if min == 0 || min > max {
+        bail!(
+            "{0}.min_width ({1}) should be at least 1 and at most {0}.max_width ({2})",
+            section,
+            min,
+            max
+        );
+    }
+    if default < min || default > max {
+        bail!(
+            "{}.width ({}) should be from {} to {}",
+            section,
+            default,
+            min,
+            max
+        );
+    }
+    Ok(())
+}
+
+// Make sure the binary at `path` runs, so a bad path shows up at startup.
+fn check_tool(name: &str, path: &str) -> Result<()> {
+    Command::new(path)
+        .arg("-version")
+        .output()
+        .map_err(|e| format_err!("{} ({}) cannot be run: {}", name, path, e))?;
+    Ok(())
+}
+
+fn env_parse<T>(name: &str, value: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env_with(name, value, |raw| {
+        raw.parse().map_err(|e| format_err!("{}", e))
+    })
+}
+
+fn env_with<T, F>(name: &str, value: &mut T, parse: F) -> Result<()>
+where
+    F: Fn(&str) -> Result<T>,
+{
+    if let Ok(raw) = env::var(name) {
+        *value = parse(&raw).map_err(|e| format_err!("Bad {}={:?}: {}", name, raw, e))?;
+    }
+    Ok(())
+}
+
+// A comma separated list.
+fn env_list<T>(name: &str, values: &mut Vec<T>) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env_with(name, values, |raw| {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse().map_err(|e| format_err!("{}: {}", item, e)))
+            .collect()
+    })
+}
+
+fn clip_format<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<ClipFormat, D::Error> {
+    let format = String::deserialize(deserializer)?;
+    ClipFormat::from(&format).map_err(D::Error::custom)
+}
+
+fn frame_format<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<FrameFormat, D::Error> {
+    let format = String::deserialize(deserializer)?;
+    FrameFormat::from(&format).map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::process;
+
+    // `validate` runs the ffmpeg binary, any program will do.
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.ffmpeg.path = "true".to_string();
+        config
+    }
+
+    // every call has its own `name`, the tests run side by side.
+    fn from_toml(name: &str, content: &str) -> Result<Config> {
+        let path = temp_dir().join(format!("ytdl-{}-{}.toml", name, process::id()));
+        fs::write(&path, content).unwrap();
+        let config = Config::from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn reads_the_file_over_the_defaults() {
+        let config = from_toml(
+            "config",
+            "[clips]\nmax_seconds = 30\nformat = \"webp\"\n\n[upstream]\nallowed_paths = [\"/v\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.clips.max_seconds, 30);
+        assert_eq!(config.clips.format, ClipFormat::from("webp").unwrap());
+        assert_eq!(config.clips.max_width, ClipsConfig::default().max_width);
+        assert_eq!(config.upstream.allowed_paths, vec!["/v"]);
+        assert_eq!(config.upstream.allowed_hosts, vec!["googlevideo.com"]);
+    }
+
+    #[test]
+    fn refuses_bad_files() {
+        assert!(from_toml("unknown-key", "[clips]\nmax_secs = 30\n").is_err());
+        assert!(from_toml("bad-format", "[clips]\nformat = \"bmp\"\n").is_err());
+        assert!(from_toml("bad-type", "[clips]\nmax_seconds = \"30\"\n").is_err());
+        assert!(Config::from_file("/nonexistent/ytdl.toml").is_err());
+    }
+
+    // the only test touching the environment, the others run beside it.
+    #[test]
+    fn the_environment_overrides_the_file() {
+        let vars = [
+            ("PORT", "9000"),
+            ("CLIP_MIN_WIDTH", "32"),
+            ("CLIP_MAX_WIDTH", "1280"),
+            ("CLIP_MAX_FPS", "60"),
+            ("FRAME_MIN_WIDTH", "16"),
+            ("FRAME_MAX_WIDTH", "3840"),
+            ("ALLOWED_HOSTS", " a.com, ,b.com "),
+        ];
+        for (name, value) in &vars {
+            env::set_var(name, value);
+        }
+        let mut config = Config::default();
+        let applied = config.apply_env();
+        env::set_var("FFMPEG_JOBS", "many");
+        let bad = Config::default().apply_env();
+        for (name, _) in &vars {
+            env::remove_var(name);
+        }
+        env::remove_var("FFMPEG_JOBS");
+        applied.unwrap();
+        assert_eq!(config.server.bind.port(), 9000);
+        assert_eq!(config.clips.min_width, 32);
+        assert_eq!(config.clips.max_width, 1280);
+        assert_eq!(config.clips.max_fps, 60);
+        assert_eq!(config.frames.min_width, 16);
+        assert_eq!(config.frames.max_width, 3840);
+        assert_eq!(config.upstream.allowed_hosts, vec!["a.com", "b.com"]);
+        assert!(bad.unwrap_err().to_string().starts_with("Bad FFMPEG_JOBS"));
+    }
+
+    #[test]
+    fn validates_the_settings() {
+        valid().validate().unwrap();
+        let broken: Vec<fn(&mut Config)> = vec![
+            |c| c.ffmpeg.jobs = 0,
+            |c| c.ffmpeg.path = "/nonexistent/ffmpeg".to_string(),
+            |c| c.clips.max_seconds = 0,
+            |c| c.clips.min_width = c.clips.max_width + 1,
+            |c| c.clips.width = c.clips.max_width + 1,
+            |c| c.clips.quality = 101,
+            |c| c.frames.width = 0,
+            |c| c.jobs.max_stored = 0,
+            |c| c.upstream.allowed_hosts.clear(),
+            |c| c.upstream.allowed_hosts = vec!["a.com/path".to_string()],
+            |c| c.upstream.allowed_paths.clear(),
+            |c| c.upstream.allowed_paths = vec!["videoplayback".to_string()],
+        ];
+        for (i, breaks) in broken.into_iter().enumerate() {
+            let mut config = valid();
+            breaks(&mut config);
+            assert!(config.validate().is_err(), "setting {} was accepted", i);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use failure::{Error, Fail};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use log::warn;
+use serde_json::json;
+use std::fmt;
+use ytdl_lib::error::{FetchError, FfmpegError, InputError, VideoError};
+
+/// The stable, machine-readable reason of an error response, sent as `code`
+/// next to the message.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorCode {
+    MissingParam,
+    InvalidParam,
+    InvalidTime,
+    InvalidUrl,
+    ClipTooLong,
+    OutputTooLarge,
+    NotFound,
+    VideoUnavailable,
+    FormatNotFound,
+    JobNotFound,
+    VideoPrivate,
+    AgeRestricted,
+    Unauthorized,
+    Forbidden,
+    RateLimited,
+    QuotaExceeded,
+    JobNotDone,
+    RangeNotSatisfiable,
+    Busy,
+    Upstream,
+    Timeout,
+    Internal,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 22] = [
+        ErrorCode::MissingParam,
+        ErrorCode::InvalidParam,
+        ErrorCode::InvalidTime,
+        ErrorCode::InvalidUrl,
+        ErrorCode::ClipTooLong,
+        ErrorCode::OutputTooLarge,
+        ErrorCode::NotFound,
+        ErrorCode::VideoUnavailable,
+        ErrorCode::FormatNotFound,
+        ErrorCode::JobNotFound,
+        ErrorCode::VideoPrivate,
+        ErrorCode::AgeRestricted,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::RateLimited,
+        ErrorCode::QuotaExceeded,
+        ErrorCode::JobNotDone,
+        ErrorCode::RangeNotSatisfiable,
+        ErrorCode::Busy,
+        ErrorCode::Upstream,
+        ErrorCode::Timeout,
+        ErrorCode::Internal,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::MissingParam => "missing_param",
+            ErrorCode::InvalidParam => "invalid_param",
+            ErrorCode::InvalidTime => "invalid_time",
+            ErrorCode::InvalidUrl => "invalid_url",
+            ErrorCode::ClipTooLong => "clip_too_long",
+            ErrorCode::OutputTooLarge => "output_too_large",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::VideoUnavailable => "video_unavailable",
+            ErrorCode::FormatNotFound => "format_not_found",
+            ErrorCode::JobNotFound => "job_not_found",
+            ErrorCode::VideoPrivate => "video_private",
+            ErrorCode::AgeRestricted => "age_restricted",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::JobNotDone => "job_not_done",
+            ErrorCode::RangeNotSatisfiable => "range_not_satisfiable",
+            ErrorCode::Busy => "busy",
+            ErrorCode::Upstream => "upstream_error",
+            ErrorCode::Timeout => "ffmpeg_timeout",
+            ErrorCode::Internal => "internal_error",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::MissingParam
+            | ErrorCode::InvalidParam
+            | ErrorCode::InvalidTime
+            | ErrorCode::InvalidUrl => StatusCode::BAD_REQUEST,
+            ErrorCode::ClipTooLong | ErrorCode::OutputTooLarge => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::NotFound
+            | ErrorCode::VideoUnavailable
+            | ErrorCode::FormatNotFound
+            | ErrorCode::JobNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::VideoPrivate | ErrorCode::AgeRestricted | ErrorCode::Forbidden => {
+                StatusCode::FORBIDDEN
+            }
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::RateLimited | ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::JobNotDone => StatusCode::CONFLICT,
+            ErrorCode::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            ErrorCode::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// An error answered with the status of its code and a JSON body like
+/// `{"error": "...", "code": "missing_param"}`.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Fail for ApiError {}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Tell apart the errors of the handlers and of `ytdl-lib`, the unknown
+    /// ones are internal errors.
+    pub fn classify(error: &Error) -> Self {
+        if let Some(error) = error.downcast_ref::<ApiError>() {
+            return error.clone();
+        }
+        if let Some(ffmpeg) = error.downcast_ref::<FfmpegError>() {
+            return ApiError::classify_ffmpeg(ffmpeg);
+        }
+        let code = if let Some(input) = error.downcast_ref::<InputError>() {
+            match input {
+                InputError::Time { .. } => ErrorCode::InvalidTime,
+                InputError::Unknown { .. } => ErrorCode::InvalidParam,
+            }
+        } else if let Some(video) = error.downcast_ref::<VideoError>() {
+            match video {
+                VideoError::AgeRestricted { .. } => ErrorCode::AgeRestricted,
+                VideoError::Private { .. } => ErrorCode::VideoPrivate,
+                VideoError::Unavailable { .. } => ErrorCode::VideoUnavailable,
+            }
+        } else if error.downcast_ref::<FetchError>().is_some() {
+            ErrorCode::Upstream
+        } else {
+            ErrorCode::Internal
+        };
+        ApiError::new(code, format!("{}", error.as_fail()))
+    }
+
+    // The stderr of ffmpeg tells about the server and the video urls, it is
+    // logged rather than answered.
+    fn classify_ffmpeg(error: &FfmpegError) -> Self {
+        // the message of a failure has its exit code and stderr already.
+        match error.stderr().filter(|stderr| !stderr.is_empty()) {
+            Some(stderr) if !matches!(error, FfmpegError::Failed { .. }) => {
+                warn!("{}, ffmpeg stderr: {}", error, stderr)
+            }
+            _ => warn!("{}", error),
+        }
+        match error {
+            FfmpegError::Failed { what, .. } if error.is_input_failure() => ApiError::new(
+                ErrorCode::Upstream,
+                format!("ffmpeg could not read the video url while {}", what),
+            ),
+            FfmpegError::Failed { what, .. } => {
+                ApiError::new(ErrorCode::Internal, format!("ffmpeg failed {}", what))
+            }
+            FfmpegError::TimedOut { .. } | FfmpegError::Stalled { .. } => {
+                ApiError::new(ErrorCode::Timeout, error.to_string())
+            }
+            FfmpegError::OutputTooLarge { .. } => {
+                ApiError::new(ErrorCode::OutputTooLarge, error.to_string())
+            }
+            FfmpegError::Cancelled { .. } => ApiError::new(ErrorCode::Internal, error.to_string()),
+        }
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        error_response(self.code, &self.message)
+    }
+}
+
+/// The JSON body of an error, with its status.
+pub fn error_response(code: ErrorCode, message: &str) -> Response<Body> {
+    let json = serde_json::to_string_pretty(&json!({
+        "error": message,
+        "code": code.name(),
+    }))
+    .unwrap();
+    Response::builder()
+        .status(code.status())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn code_of<F: Fail>(error: F) -> ErrorCode {
+        ApiError::classify(&error.into()).code
+    }
+
+    #[test]
+    fn classifies_the_errors() {
+        let reason = String::new;
+        assert_eq!(
+            code_of(ApiError::new(ErrorCode::JobNotFound, "no job")),
+            ErrorCode::JobNotFound
+        );
+        assert_eq!(
+            code_of(InputError::Time { reason: reason() }),
+            ErrorCode::InvalidTime
+        );
+        assert_eq!(
+            code_of(InputError::Unknown {
+                what: "format",
+                value: "avi".to_string(),
+                expected: "gif",
+            }),
+            ErrorCode::InvalidParam
+        );
+        assert_eq!(
+            code_of(VideoError::AgeRestricted { reason: reason() }),
+            ErrorCode::AgeRestricted
+        );
+        assert_eq!(
+            code_of(VideoError::Private { reason: reason() }),
+            ErrorCode::VideoPrivate
+        );
+        assert_eq!(
+            code_of(VideoError::Unavailable { reason: reason() }),
+            ErrorCode::VideoUnavailable
+        );
+        assert_eq!(
+            code_of(FfmpegError::TimedOut {
+                what: "clip".to_string(),
+                after: Duration::from_secs(1),
+                stderr: String::new(),
+            }),
+            ErrorCode::Timeout
+        );
+        assert_eq!(
+            code_of(FfmpegError::OutputTooLarge {
+                what: "clip".to_string(),
+                limit: 1,
+            }),
+            ErrorCode::OutputTooLarge
+        );
+        assert_eq!(
+            code_of(FetchError::Status {
+                url: "u".to_string(),
+                status: 404,
+                attempts: 1,
+            }),
+            ErrorCode::Upstream
+        );
+        let failed = |stderr: &str| FfmpegError::Failed {
+            what: "making a gif".to_string(),
+            code: Some(1),
+            stderr: stderr.to_string(),
+        };
+        let upstream = ApiError::classify(
+            &failed("https://r1.googlevideo.com/videoplayback?key=secret: Server returned 403 Forbidden")
+                .into(),
+        );
+        assert_eq!(upstream.code, ErrorCode::Upstream);
+        assert!(!upstream.message.contains("secret"), "{}", upstream.message);
+        assert_eq!(
+            code_of(failed("Unknown encoder 'libwebp'")),
+            ErrorCode::Internal
+        );
+        let unknown = failure::err_msg("something broke");
+        assert_eq!(ApiError::classify(&unknown).code, ErrorCode::Internal);
+    }
+
+    #[test]
+    fn every_code_has_its_own_name_and_an_error_status() {
+        let names: HashSet<_> = ErrorCode::ALL.iter().map(|code| code.name()).collect();
+        assert_eq!(names.len(), ErrorCode::ALL.len());
+        for code in ErrorCode::ALL.iter() {
+            let status = code.status();
+            assert!(
+                status.is_client_error() || status.is_server_error(),
+                "{:?}",
+                code
+            );
+        }
+        assert_eq!(ErrorCode::MissingParam.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(ErrorCode::VideoPrivate.status(), StatusCode::FORBIDDEN);
+        assert_eq!(ErrorCode::VideoUnavailable.status(), StatusCode::NOT_FOUND);
+        assert_eq!(ErrorCode::Unauthorized.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            ErrorCode::RateLimited.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(ErrorCode::Busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ErrorCode::Upstream.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(ErrorCode::Timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            ErrorCode::Internal.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn the_body_has_the_code_and_the_message() {
+        let response = ApiError::new(ErrorCode::ClipTooLong, "too long").response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    }
+}
@@ -0,0 +1,257 @@
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use ytdl_lib::clip::ClipFormat;
+
+// how long the finished jobs and their results are kept around.
+const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+/// An extraction submitted through `POST /jobs`.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// From 0 to 1, as reported by ffmpeg.
+    pub progress: f64,
+    pub error: Option<String>,
+    pub format: ClipFormat,
+    pub result: Option<Arc<Vec<u8>>>,
+    finished: Option<Instant>,
+}
+
+impl Job {
+    /// The body of `GET /jobs/{id}`, with the progress in percent like the events.
+    pub fn to_json(&self) -> Value {
+        let mut status = json!({
+            "id": self.id,
+            "status": self.status.name(),
+            "progress": percent(self.progress),
+        });
+        if let Some(error) = &self.error {
+            status["error"] = json!(error);
+        }
+        if self.status == JobStatus::Done {
+            status["result_url"] = json!(format!("/jobs/{}/result", self.id));
+        }
+        status
+    }
+}
+
+/// What happened to a job, pushed to its subscribers.
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    Status(JobStatus),
+    /// The done percentage.
+    Progress(u8),
+    Done,
+    Failed(String),
+}
+
+/// The jobs known by the server, kept in memory. At most `max_jobs` of them,
+/// with `max_result_bytes` of results.
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Job>>,
+    // always locked after `jobs`.
+    subscribers: Mutex<HashMap<String, Vec<UnboundedSender<JobEvent>>>>,
+    max_jobs: usize,
+    max_result_bytes: u64,
+}
+
+impl JobStore {
+    pub fn new(max_jobs: usize, max_result_bytes: u64) -> Self {
+        JobStore {
+            jobs: Mutex::default(),
+            subscribers: Mutex::default(),
+            max_jobs,
+            max_result_bytes,
+        }
+    }
+
+    /// Add a queued job, returns its id. `None` when the store is full, until
+    /// the old results expire.
+    pub fn create(&self, format: ClipFormat) -> Option<String> {
+        let id = Uuid::new_v4().to_simple().to_string();
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| job.finished.is_none_or(|at| at.elapsed() < JOB_TTL));
+        let result_bytes: u64 = jobs
+            .values()
+            .filter_map(|job| job.result.as_ref())
+            .map(|result| result.len() as u64)
+            .sum();
+        if jobs.len() >= self.max_jobs || result_bytes >= self.max_result_bytes {
+            return None;
+        }
+        jobs.insert(
+            id.clone(),
+            Job {
+                id: id.clone(),
+                status: JobStatus::Queued,
+                progress: 0.0,
+                error: None,
+                format,
+                result: None,
+                finished: None,
+            },
+        );
+        Some(id)
+    }
+
+    /// Forget a job that could not be queued.
+    pub fn remove(&self, id: &str) {
+        self.jobs.lock().unwrap().remove(id);
+    }
+
+    /// How many jobs have the given status.
+    pub fn count(&self, status: JobStatus) -> usize {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values().filter(|job| job.status == status).count()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Follow the events of a job, starting with its current state. The stream
+    /// ends once the job is done or failed.
+    pub fn subscribe(&self, id: &str) -> Option<UnboundedReceiver<JobEvent>> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+        let (sender, receiver) = mpsc::unbounded();
+        let mut events = vec![
+            JobEvent::Status(job.status),
+            JobEvent::Progress(percent(job.progress)),
+        ];
+        events.extend(finished_event(job));
+        for event in events {
+            let _ = sender.unbounded_send(event);
+        }
+        if !is_finished(job) {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.entry(id.to_string()).or_default().push(sender);
+        }
+        Some(receiver)
+    }
+
+    pub fn set_running(&self, id: &str) {
+        self.update(id, |job| {
+            job.status = JobStatus::Running;
+            Some(JobEvent::Status(job.status))
+        });
+    }
+
+    pub fn set_progress(&self, id: &str, progress: f64) {
+        self.update(id, |job| {
+            let before = percent(job.progress);
+            job.progress = progress;
+            // ffmpeg reports twice a second, only tell about the visible changes.
+            match percent(progress) {
+                now if now != before => Some(JobEvent::Progress(now)),
+                _ => None,
+            }
+        });
+    }
+
+    pub fn finish(&self, id: &str, result: Result<Vec<u8>, String>) {
+        self.update(id, |job| {
+            match result {
+                Ok(body) => {
+                    job.status = JobStatus::Done;
+                    job.progress = 1.0;
+                    job.result = Some(Arc::new(body));
+                }
+                Err(error) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(error);
+                }
+            }
+            job.finished = Some(Instant::now());
+            finished_event(job)
+        });
+    }
+
+    // Change a job and push the resulting event to its subscribers.
+    fn update<F: FnOnce(&mut Job) -> Option<JobEvent>>(&self, id: &str, update: F) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get_mut(id) {
+            Some(job) => job,
+            None => return,
+        };
+        let event = match update(job) {
+            Some(event) => event,
+            None => return,
+        };
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if is_finished(job) {
+            // dropping the senders ends the streams.
+            for sender in subscribers.remove(id).unwrap_or_default() {
+                let _ = sender.unbounded_send(JobEvent::Status(job.status));
+                let _ = sender.unbounded_send(event.clone());
+            }
+        } else if let Some(senders) = subscribers.get_mut(id) {
+            senders.retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+        }
+    }
+}
+
+fn is_finished(job: &Job) -> bool {
+    job.status == JobStatus::Done || job.status == JobStatus::Failed
+}
+
+fn finished_event(job: &Job) -> Option<JobEvent> {
+    match job.status {
+        JobStatus::Done => Some(JobEvent::Done),
+        JobStatus::Failed => Some(JobEvent::Failed(job.error.clone().unwrap_or_default())),
+        _ => None,
+    }
+}
+
+fn percent(progress: f64) -> u8 {
+    (progress * 100.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_store_is_capped() {
+        let store = JobStore::new(2, 1024);
+        let first = store.create(ClipFormat::Gif).unwrap();
+        let second = store.create(ClipFormat::Gif).unwrap();
+        assert_eq!(store.create(ClipFormat::Gif), None);
+        store.remove(&second);
+        assert!(store.create(ClipFormat::Gif).is_some());
+        assert!(store.get(&first).is_some());
+    }
+
+    #[test]
+    fn the_results_are_capped() {
+        let store = JobStore::new(10, 1024);
+        let id = store.create(ClipFormat::Gif).unwrap();
+        store.finish(&id, Ok(vec![0; 1024]));
+        assert_eq!(store.create(ClipFormat::Gif), None);
+        assert_eq!(store.get(&id).unwrap().status, JobStatus::Done);
+    }
+}
@@ -0,0 +1,144 @@
+use futures::{Future, Poll};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+thread_local! {
+    // the id of the request being served on this thread, if any.
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Writes every log record to stderr as a JSON line, tagged with the id of the
+/// request it was logged for.
+struct JsonLogger {
+    default: LevelFilter,
+    // by target prefix, like `ytdl_lib=debug`.
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl JsonLogger {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| target == prefix || target.starts_with(&format!("{}::", prefix)))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = json!({
+            "time": unix_millis(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+            "request_id": current_request_id(),
+        });
+        let stderr = io::stderr();
+        let _ = writeln!(stderr.lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Install the JSON logger, filtered like `env_logger` with `RUST_LOG`
+/// (`info`, `ytdl_lib=debug,hyper=warn`, ...), `info` by default.
+pub fn init() -> Result<(), log::SetLoggerError> {
+    let spec = env::var("RUST_LOG").unwrap_or_default();
+    let mut logger = JsonLogger {
+        default: LevelFilter::Info,
+        targets: Vec::new(),
+    };
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                if let Ok(level) = level.parse() {
+                    logger.targets.push((target.to_string(), level));
+                }
+            }
+            None => match directive.parse() {
+                Ok(level) => logger.default = level,
+                // a bare target enables everything it logs.
+                Err(_) => logger
+                    .targets
+                    .push((directive.to_string(), LevelFilter::Trace)),
+            },
+        }
+    }
+    let max = logger
+        .targets
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(logger.default, Ord::max);
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(max);
+    Ok(())
+}
+
+/// The id of the request being served on this thread.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+// Puts back the previous request id, even if the scope panics.
+struct Restore(Option<String>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+/// Run `f` with `request_id` attached to the logs of this thread.
+pub fn scope<F, T>(request_id: Option<String>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let previous = REQUEST_ID.with(|id| id.replace(request_id));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// A future that logs with the id of its request, whatever thread polls it.
+pub struct WithRequestId<F> {
+    request_id: String,
+    inner: F,
+}
+
+impl<F> WithRequestId<F> {
+    pub fn new(request_id: String, inner: F) -> Self {
+        WithRequestId { request_id, inner }
+    }
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &mut self.inner;
+        scope(Some(self.request_id.clone()), || inner.poll())
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
@@ -1,115 +1,195 @@
-use regex::Regex;
 use failure::{err_msg, Error};
-use futures::{future, Future};
-use hyper::service::service_fn;
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use std::collections::HashMap;
-use std::env;
-use std::fmt;
-use std::ops::Sub;
-use std::process::Command;
-use url::form_urlencoded;
-use ytdl_lib::Video;
+use futures::sync::mpsc;
+use futures::{future, stream, Future, Sink, Stream};
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, LOCATION, RANGE, RETRY_AFTER,
+};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
 use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
 use serde_json::json;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::time::Instant;
+use url::{form_urlencoded, Url};
+use uuid::Uuid;
+use ytdl_lib::client::HttpClient;
+use ytdl_lib::clip::{
+    make_clip, make_clip_with_progress, make_frame, set_ffmpeg_path, transcode_mp3, ClipFormat,
+    ClipOptions, Dither, FrameFormat, GifPalette, StatsMode, Timestamp,
+};
+use ytdl_lib::error::FetchError;
+use ytdl_lib::ffmpeg::Cancel;
+use ytdl_lib::{mime_extension, source_url, token_cache_stats, VideoInfo};
+
+mod auth;
+mod cache;
+mod config;
+mod error;
+mod jobs;
+mod logging;
+mod metrics;
+mod openapi;
+mod pool;
+mod ratelimit;
+mod routes;
+
+use crate::auth::{Auth, AuthError, Caller};
+use crate::cache::{CachedVideo, VideoCache};
+use crate::config::Config;
+use crate::error::{error_response, ApiError, ErrorCode};
+use crate::jobs::{Job, JobEvent, JobStatus, JobStore};
+use crate::logging::WithRequestId;
+use crate::metrics::{write_metric, METRICS};
+use crate::pool::{CancelOnDrop, JobPool};
+use crate::ratelimit::{client_ip, Bucket, RateLimiter};
+use crate::routes::{routes, Endpoint, Route};
 
 type Result<T> = std::result::Result<T, Error>;
 type BoxFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
-const TIME_REGEX_STR: &str = r"(?m)^(?:(?:([01]?\d|2[0-3]):)?([0-5]?\d):)?([0-5]?\d)$";
-const GOOGLE_VIDEO_URL: &str = r#"^https?.+?\.googlevideo\.com/videoplayback"#;
+type HandlerFuture = Box<dyn Future<Item = Response<Body>, Error = Error> + Send>;
+// what we tell the clients to wait when all the ffmpeg slots are busy, in seconds.
+const BUSY_RETRY_AFTER: u64 = 10;
+const WORKERS_BUSY: &str = "All the ffmpeg workers are busy, try again later.";
+const JOBS_FULL: &str = "Too many jobs are queued or kept, try again later.";
+// how many chunks of transcoded audio wait for a slow client before ffmpeg blocks.
+const MP3_CHANNEL_CHUNKS: usize = 16;
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// the longest request id we take from the clients, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
 
 lazy_static! {
-    static ref TIME_REGEX: Regex = Regex::new(&TIME_REGEX_STR).unwrap();
-    static ref GOOGLE_VIDEO_URL_REGEX: Regex = Regex::new(&GOOGLE_VIDEO_URL).unwrap();
-    static ref HTTP_HELP: String = serde_json::to_string_pretty(&json!({
-        "endpoints": [
-            {
-                "path": "/",
-                "method": "GET",
-                "required_params": "",
-                "description": "View this endpoint."
-            },
-            {
-                "path": "/watch",
-                "method": "GET",
-                "required_params": {
-                    "v": "the video id"
-                },
-                "description": "get the video downloads urls"
-            },
-            {
-                "path": "/extract",
-                "method": "GET",
-                "required_params": {
-                    "url": "the exteracted video url from /watch endpoint",
-                    "start": "the start time in HH:MM:SS format",
-                    "end": "the end time in HH:MM:SS format"
-                },
-                "description": "extract gif from the video"
-            }
-        ]
-    })).unwrap();
+    static ref CONFIG: Config = Config::load().unwrap_or_else(|error| {
+        error!("Invalid configuration: {}", error);
+        process::exit(1)
+    });
+    static ref FFMPEG_POOL: JobPool = JobPool::new(CONFIG.ffmpeg.jobs, CONFIG.jobs.max_queued);
+    static ref WATCH_CACHE: VideoCache = VideoCache::new(CONFIG.cache.watch_size);
+    static ref CLIENT: HttpClient = HttpClient::default();
+    static ref JOBS: JobStore = JobStore::new(
+        CONFIG.jobs.max_stored,
+        CONFIG.jobs.max_results_mb * 1024 * 1024
+    );
+    static ref AUTH: Auth = match &CONFIG.auth.keys_file {
+        Some(path) => Auth::from_file(path).unwrap_or_else(|error| {
+            error!("Invalid configuration: {}", error);
+            process::exit(1)
+        }),
+        None => Auth::default(),
+    };
+    static ref RATE_LIMITER: RateLimiter = RateLimiter::new(
+        CONFIG.rate_limit.cheap_per_minute,
+        CONFIG.rate_limit.expensive_per_minute
+    );
+    static ref ROUTES: Vec<Route> = routes(&CONFIG);
+    static ref HTTP_HELP: String = serde_json::to_string_pretty(&openapi::help(&ROUTES)).unwrap();
+    static ref OPENAPI: String = serde_json::to_string_pretty(&openapi::document(&ROUTES)).unwrap();
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-struct HumanTime(i16, i8, i8);
-
-impl Sub for HumanTime {
-    type Output = HumanTime;
-
-    fn sub(self, other: HumanTime) -> HumanTime {
-        let h = self.0 - other.0;
-        let m = self.1 - other.1;
-        let s = self.2 - other.2;
-        HumanTime(h, m, s)
-    }
+// Time and log every request, by route and status, under its request id.
+fn handle(req: Request<Body>, remote: IpAddr) -> BoxFuture {
+    let request_id = request_id(&req);
+    let route = routes::find(&ROUTES, req.method(), req.uri().path());
+    let label = route.map_or("unknown", |route| route.path);
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let started = Instant::now();
+    let header = HeaderValue::from_str(&request_id).unwrap();
+    let response = logging::scope(Some(request_id.clone()), || limit(req, remote, route));
+    let response = response.map(move |mut response| {
+        let status = response.status();
+        let elapsed = started.elapsed();
+        METRICS.observe_request(label, status.as_u16(), elapsed);
+        info!(
+            "{} {} {} in {}ms",
+            method,
+            path,
+            status.as_u16(),
+            elapsed.as_millis()
+        );
+        response.headers_mut().insert(REQUEST_ID_HEADER, header);
+        response
+    });
+    Box::new(WithRequestId::new(request_id, response))
 }
 
-impl fmt::Display for HumanTime {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}:{}", self.0, self.1, self.2)
+// The id of a request, the one the client sent in `X-Request-Id` or a new one.
+fn request_id(req: &Request<Body>) -> String {
+    let sent = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars().all(|c| c.is_ascii_graphic())
+        });
+    match sent {
+        Some(id) => id.to_string(),
+        None => Uuid::new_v4().to_simple().to_string(),
     }
 }
 
-impl HumanTime {
-    // parse time from format HH:MM:SS to use it in ffmpeg
-    pub fn from(time: &str) -> Result<HumanTime> {
-        let captures = TIME_REGEX.captures(time).ok_or_else(|| {
-            err_msg(
-                r"Wrong Start or End Time format.
-        it should be on 'HH:MM:SS' format, can you check it again ?",
-            )
-        })?;
-        let h: i16 = captures.get(1).map_or("0", |v| v.as_str()).parse()?;
-        let m: i8 = captures.get(2).map_or("0", |v| v.as_str()).parse()?;
-        let s: i8 = captures.get(3).map_or("0", |v| v.as_str()).parse()?;
-        Ok(HumanTime(h, m, s))
+// Check who is calling and how often before routing the request.
+fn limit(mut req: Request<Body>, remote: IpAddr, route: Option<&'static Route>) -> BoxFuture {
+    if let Err(denied) = AUTH.authorize(&mut req, route) {
+        return Box::new(future::ok(denied.response()));
     }
-
-    pub fn calculate_duration(&self, since: HumanTime) -> Result<HumanTime> {
-        let result = since - *self;
-        if result.0 < 0 || result.1 < 0 || result.2 < 0 {
-            Err(err_msg(
-                "Doh :( , look at start and end time, and try again, Idiot :'D ",
-            ))?
-        }
-        Ok(result)
+    let client = match req.extensions().get::<Caller>() {
+        Some(caller) => format!("key:{}", caller.key),
+        None => format!(
+            "ip:{}",
+            client_ip(req.headers(), remote, &CONFIG.rate_limit.trusted_proxies)
+        ),
+    };
+    let bucket = route.map_or(Bucket::Cheap, |route| route.bucket);
+    let decision = match RATE_LIMITER.take(&client, bucket) {
+        Some(decision) => decision,
+        None => return router(req, route),
+    };
+    if !decision.allowed {
+        let mut response =
+            error_response(ErrorCode::RateLimited, "Too many requests, slow down a bit");
+        decision.add_headers(response.headers_mut());
+        return Box::new(future::ok(response));
     }
+    let response = router(req, route).map(move |mut response| {
+        decision.add_headers(response.headers_mut());
+        response
+    });
+    Box::new(response)
 }
 
-fn router(req: Request<Body>) -> BoxFuture {
+fn router(req: Request<Body>, route: Option<&'static Route>) -> BoxFuture {
     let response;
     let internal_server_error = |error: Error| {
-        let json = serde_json::to_string_pretty(&json!({ "error": format!("{}", error.as_fail()) }))
-            .unwrap();
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("Content-Type", "application/json")
-            .body(Body::from(json))
-            .unwrap()
-    };
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/") => {
+        if let Some(denied) = error.downcast_ref::<AuthError>() {
+            return denied.response();
+        }
+        let error = ApiError::classify(&error);
+        if error.code.status().is_server_error() {
+            warn!("Request failed: {}", error);
+        }
+        error.response()
+    };
+    // the jobs can send their params in the body, they are checked once read.
+    if let Some(route) = route.filter(|route| route.endpoint != Endpoint::SubmitJob) {
+        let query: HashMap<String, String> =
+            form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect();
+        if let Err(error) = route.check_params(&query) {
+            return Box::new(future::ok(error.response()));
+        }
+    }
+    match route.map(|route| route.endpoint) {
+        Some(Endpoint::Help) => {
             let body: &str = &HTTP_HELP.as_str();
             response = Response::builder()
                 .status(StatusCode::OK)
@@ -118,18 +198,57 @@ fn router(req: Request<Body>) -> BoxFuture {
                 .unwrap();
         }
 
-        (&Method::GET, "/watch") => {
-            response = get_video(&req).unwrap_or_else(internal_server_error);
+        Some(Endpoint::OpenApi) => {
+            let body: &str = OPENAPI.as_str();
+            response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
         }
 
-        (&Method::GET, "/extract") => {
-            response = extract_gif(&req).unwrap_or_else(internal_server_error);
+        Some(Endpoint::Watch) => {
+            let watch = get_video(&req).or_else(move |error| Ok(internal_server_error(error)));
+            return Box::new(watch);
         }
-        _ => {
-            response = Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::empty())
-                .unwrap();
+
+        Some(Endpoint::Extract) => {
+            let clip = extract_clip(&req).or_else(move |error| Ok(internal_server_error(error)));
+            return Box::new(clip);
+        }
+
+        Some(Endpoint::Download) => {
+            let download = download(&req).or_else(move |error| Ok(internal_server_error(error)));
+            return Box::new(download);
+        }
+
+        Some(Endpoint::Audio) => {
+            let audio = audio(&req).or_else(move |error| Ok(internal_server_error(error)));
+            return Box::new(audio);
+        }
+
+        Some(Endpoint::Frame) => {
+            let frame = extract_frame(&req).or_else(move |error| Ok(internal_server_error(error)));
+            return Box::new(frame);
+        }
+
+        Some(Endpoint::Metrics) => {
+            response = metrics().unwrap_or_else(internal_server_error);
+        }
+
+        Some(Endpoint::SubmitJob) => {
+            let job = submit_job(req, route.unwrap())
+                .or_else(move |error| Ok(internal_server_error(error)));
+            return Box::new(job);
+        }
+
+        Some(endpoint @ Endpoint::JobStatus)
+        | Some(endpoint @ Endpoint::JobResult)
+        | Some(endpoint @ Endpoint::JobEvents) => {
+            response = job_route(req.uri().path(), endpoint).unwrap_or_else(internal_server_error);
+        }
+        None => {
+            response = error_response(ErrorCode::NotFound, "Unknown endpoint, see / for the list");
         }
     }
     Box::new(future::ok(response))
@@ -137,108 +256,736 @@ fn router(req: Request<Body>) -> BoxFuture {
 
 fn validate_query<'a>(query: &'a HashMap<String, String>, key: &str) -> Result<&'a String> {
     let err = format!("Expected url to have query key '{}'", key);
-    query.get(key).ok_or_else(|| err_msg(err))
+    query
+        .get(key)
+        .ok_or_else(|| ApiError::new(ErrorCode::MissingParam, err).into())
 }
 
-fn get_video(req: &Request<Body>) -> Result<Response<Body>> {
-    let query = req
-        .uri()
-        .query()
-        .ok_or_else(|| err_msg("Expected to have query string in url"))?;
+fn parse_query(req: &Request<Body>) -> Result<HashMap<String, String>> {
+    let query = req.uri().query().ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::MissingParam,
+            "Expected to have query string in url",
+        )
+    })?;
     let hash_query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
         .into_owned()
         .collect();
-    let video_id = validate_query(&hash_query, "v")?;
-    let mut video = Video::new(&video_id);
-    video.initialize()?;
-    let video_sources = video
-        .video_sources()
-        .ok_or_else(|| err_msg("Ops, Error While Getting Video Sources."))?;
-    let body = serde_json::to_string_pretty(video_sources)?;
+    Ok(hash_query)
+}
+
+fn get_video(req: &Request<Body>) -> impl Future<Item = Response<Body>, Error = Error> {
+    let video_id = parse_query(req).and_then(|query| Ok(validate_query(&query, "v")?.clone()));
+    future::result(video_id)
+        .and_then(|video_id| WATCH_CACHE.video(&video_id))
+        .and_then(|video| {
+            let body = serde_json::to_string_pretty(&video.sources)?;
+            let response = Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(Body::from(body))?;
+            Ok(response)
+        })
+}
+
+fn download(req: &Request<Body>) -> HandlerFuture {
+    let params = parse_query(req).and_then(|query| {
+        let video_id = validate_query(&query, "v")?.clone();
+        let itag = validate_query(&query, "itag")?.clone();
+        Ok((video_id, itag))
+    });
+    let (video_id, itag) = match params {
+        Ok(params) => params,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    let range = request_range(req);
+    let response = WATCH_CACHE
+        .video(&video_id)
+        .and_then(move |video| -> HandlerFuture {
+            match video.find_source(&itag) {
+                Some(source) => proxy_source(&video, &video_id, source, range),
+                None => {
+                    let message = format!("The video has no format with itag {}", itag);
+                    let error = ApiError::new(ErrorCode::FormatNotFound, message);
+                    Box::new(future::err(error.into()))
+                }
+            }
+        });
+    Box::new(response)
+}
+
+fn audio(req: &Request<Body>) -> HandlerFuture {
+    let params = parse_query(req).and_then(|query| {
+        let video_id = validate_query(&query, "v")?.clone();
+        let mp3 = match query.get("format").map(String::as_str) {
+            None | Some("original") => false,
+            Some("mp3") => true,
+            Some(other) => Err(ApiError::new(
+                ErrorCode::InvalidParam,
+                format!("Unknown format '{}', it should be original or mp3", other),
+            ))?,
+        };
+        Ok((video_id, mp3))
+    });
+    let (video_id, mp3) = match params {
+        Ok(params) => params,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    let range = request_range(req);
+    let response = WATCH_CACHE
+        .video(&video_id)
+        .and_then(move |video| -> HandlerFuture {
+            let source = match video.best_audio() {
+                Some(source) => source,
+                None => {
+                    let error =
+                        ApiError::new(ErrorCode::FormatNotFound, "Ops, this video has no audio.");
+                    return Box::new(future::err(error.into()));
+                }
+            };
+            if mp3 {
+                stream_mp3(&video, &video_id, source)
+            } else {
+                proxy_source(&video, &video_id, source, range)
+            }
+        });
+    Box::new(response)
+}
+
+fn request_range(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(str::to_string)
+}
+
+// Stream a source from upstream to the client, passing the range headers both ways.
+fn proxy_source(
+    video: &CachedVideo,
+    video_id: &str,
+    source: &VideoInfo,
+    range: Option<String>,
+) -> HandlerFuture {
+    let url = match source_url(source) {
+        Some(url) => url,
+        None => {
+            let error = ApiError::new(ErrorCode::Upstream, "Ops, this format has no url.");
+            return Box::new(future::err(error.into()));
+        }
+    };
+    let extension = source.get("type").map_or("mp4", |t| mime_extension(t));
+    let title = video.title.as_ref().map_or(video_id, String::as_str);
+    let disposition = content_disposition(title, extension);
+    let response = CLIENT
+        .get_range_async(&url, range.as_deref())
+        .then(move |upstream| {
+            let upstream = match upstream {
+                Ok(upstream) => upstream,
+                Err(error) => return range_not_satisfiable(error),
+            };
+            let mut response = Response::builder();
+            response
+                .status(upstream.status().as_u16())
+                .header(CONTENT_DISPOSITION, disposition.as_str());
+            // what the client needs to seek and resume.
+            for name in &[CONTENT_TYPE, CONTENT_LENGTH, CONTENT_RANGE, ACCEPT_RANGES] {
+                if let Some(value) = upstream.headers().get(name.as_str()) {
+                    response.header(name, value.as_bytes());
+                }
+            }
+            Ok(response.body(Body::wrap_stream(upstream.into_body()))?)
+        });
+    Box::new(response)
+}
+
+// Transcode a source to mp3 on the ffmpeg pool, the body is sent as it is made.
+// The headers wait for the first chunk, so ffmpeg failing to start is answered
+// as an error rather than an empty mp3.
+fn stream_mp3(video: &CachedVideo, video_id: &str, source: &VideoInfo) -> HandlerFuture {
+    let url = match source_url(source) {
+        Some(url) => url,
+        None => {
+            let error = ApiError::new(ErrorCode::Upstream, "Ops, this format has no url.");
+            return Box::new(future::err(error.into()));
+        }
+    };
+    let (sender, receiver) = mpsc::channel(MP3_CHANNEL_CHUNKS);
+    let cancel = Cancel::new();
+    let job_cancel = cancel.clone();
+    let job = FFMPEG_POOL.try_spawn(move || {
+        let limits = CONFIG.ffmpeg.stream_limits();
+        match transcode_mp3(&url, &mut ChannelWriter(sender), &limits, &job_cancel) {
+            Ok(written) => {
+                debug!("Streamed {} bytes of mp3", written);
+                Ok(())
+            }
+            Err(error) => {
+                // past the first chunk nobody else sees it.
+                warn!("Streaming mp3 failed: {}", ApiError::classify(&error));
+                Err(error)
+            }
+        }
+    });
+    let job = match job {
+        Some(job) => job,
+        None => return Box::new(future::result(service_unavailable(WORKERS_BUSY))),
+    };
+    let title = video.title.as_ref().map_or(video_id, String::as_str);
+    let disposition = content_disposition(title, "mp3");
+    let first = CancelOnDrop::new(receiver.into_future(), cancel.clone());
+    let response = first.then(move |first| -> HandlerFuture {
+        match first {
+            Ok((Some(chunk), rest)) => {
+                // the body streams on its own, nobody waits on the job.
+                job.forget();
+                let body = stream::once(Ok(chunk))
+                    .chain(rest)
+                    .map_err(|()| io::Error::from(io::ErrorKind::BrokenPipe));
+                let body = CancelOnDrop::new(body, cancel);
+                let response = Response::builder()
+                    .header(CONTENT_TYPE, "audio/mpeg")
+                    .header(CONTENT_DISPOSITION, disposition.as_str())
+                    .status(StatusCode::OK)
+                    .body(Body::wrap_stream(body))
+                    .map_err(Error::from);
+                Box::new(future::result(response))
+            }
+            // ffmpeg is done without writing anything, its error is the answer.
+            _ => Box::new(job.and_then(|()| -> Result<Response<Body>> {
+                Err(ApiError::new(
+                    ErrorCode::Upstream,
+                    "ffmpeg found no audio to transcode at the video url",
+                ))?
+            })),
+        }
+    });
+    Box::new(response)
+}
+
+// Sends what is written to the body of a response, fails once the client is gone.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sender = self.0.clone();
+        self.0 = sender
+            .send(buf.to_vec())
+            .wait()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A range past the end of the format comes back from upstream as a 416, pass
+// it through instead of failing.
+fn range_not_satisfiable(error: Error) -> Result<Response<Body>> {
+    METRICS.upstream_error(&error);
+    match error.downcast::<FetchError>() {
+        Ok(FetchError::Status { status: 416, .. }) => Ok(error_response(
+            ErrorCode::RangeNotSatisfiable,
+            "The range is past the end of the file",
+        )),
+        Ok(error) => Err(error.into()),
+        Err(error) => Err(error),
+    }
+}
+
+// Name the downloaded file after the video title, with an ascii fallback for
+// the old clients and the full title for the others (RFC 6266).
+fn content_disposition(title: &str, extension: &str) -> String {
+    let ascii: String = title
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if !r#""\/:*?<>|"#.contains(c) => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = format!("{}.{}", title, extension)
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!(
+        r#"attachment; filename="{}.{}"; filename*=UTF-8''{}"#,
+        ascii, extension, encoded
+    )
+}
+
+// Queue an extraction, it takes the same parameters as /extract, in the query
+// string or as a form.
+fn submit_job(req: Request<Body>, route: &'static Route) -> HandlerFuture {
+    let query = req.uri().query().unwrap_or_default().to_string();
+    let caller = req.extensions().get::<Caller>().cloned();
+    let submit = req.into_body().concat2().from_err().and_then(move |body| {
+        let mut params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        params.extend(form_urlencoded::parse(&body).into_owned());
+        route.check_params(&params)?;
+        let (video_url, start, duration, options) = parse_clip(&params)?;
+        let seconds = duration.ceil_secs();
+        AUTH.charge_clip(caller.as_ref(), seconds)?;
+        let id = match JOBS.create(options.format) {
+            Some(id) => id,
+            None => {
+                AUTH.refund_clip(caller.as_ref(), seconds);
+                return service_unavailable(JOBS_FULL);
+            }
+        };
+        let (started_id, job_id) = (id.clone(), id.clone());
+        let job_caller = caller.clone();
+        let queued = FFMPEG_POOL.try_queue(
+            move || JOBS.set_running(&started_id),
+            move || {
+                // a panic still fails the job, its events would never end.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    make_clip_with_progress(
+                        &video_url,
+                        &start,
+                        &duration,
+                        &options,
+                        &CONFIG.ffmpeg.limits(),
+                        &Cancel::new(),
+                        |p| JOBS.set_progress(&job_id, p),
+                    )
+                }))
+                .unwrap_or_else(|_| Err(err_msg("The extraction crashed")));
+                match result {
+                    Ok(body) => {
+                        JOBS.finish(&job_id, Ok(body));
+                        Ok(())
+                    }
+                    Err(error) => {
+                        AUTH.refund_clip(job_caller.as_ref(), seconds);
+                        JOBS.finish(&job_id, Err(ApiError::classify(&error).message));
+                        Err(error)
+                    }
+                }
+            },
+        );
+        if !queued {
+            JOBS.remove(&id);
+            AUTH.refund_clip(caller.as_ref(), seconds);
+            return service_unavailable(JOBS_FULL);
+        }
+        let status_url = format!("/jobs/{}", id);
+        let body = serde_json::to_string_pretty(&json!({
+            "id": id,
+            "status": JobStatus::Queued.name(),
+            "status_url": status_url,
+        }))?;
+        let response = Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(CONTENT_TYPE, "application/json")
+            .header(LOCATION, status_url.as_str())
+            .body(Body::from(body))?;
+        Ok(response)
+    });
+    Box::new(submit)
+}
+
+// `/jobs/{id}` and `/jobs/{id}/result`.
+fn job_route(path: &str, endpoint: Endpoint) -> Result<Response<Body>> {
+    // the path is /jobs/{id} with maybe one more segment.
+    let id = path.split('/').nth(2).unwrap_or_default();
+    let job = match JOBS.get(id) {
+        Some(job) => job,
+        None => {
+            let message = format!("No job with id '{}'", id);
+            return Ok(error_response(ErrorCode::JobNotFound, &message));
+        }
+    };
+    match endpoint {
+        Endpoint::JobResult => job_result(&job),
+        Endpoint::JobEvents => job_events(&job),
+        _ => job_status(&job),
+    }
+}
+
+fn job_status(job: &Job) -> Result<Response<Body>> {
     let response = Response::builder()
-        .header("Content-Type", "application/json")
         .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&job.to_json())?))?;
+    Ok(response)
+}
+
+fn job_result(job: &Job) -> Result<Response<Body>> {
+    let body = match &job.result {
+        Some(body) => body,
+        None => {
+            let message = format!("The job is {}, there is no result", job.status.name());
+            return Ok(error_response(ErrorCode::JobNotDone, &message));
+        }
+    };
+    let disposition = format!(
+        r#"inline; filename="{}.{}""#,
+        job.id,
+        job.format.extension()
+    );
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, job.format.content_type())
+        .header(CONTENT_DISPOSITION, disposition.as_str())
+        .body(Body::from(body.to_vec()))?;
+    Ok(response)
+}
+
+// Push the events of a job as Server-Sent Events until it is done or failed.
+fn job_events(job: &Job) -> Result<Response<Body>> {
+    let events = JOBS
+        .subscribe(&job.id)
+        .ok_or_else(|| ApiError::new(ErrorCode::JobNotFound, "The job is gone"))?;
+    let id = job.id.clone();
+    let body = events
+        .map(move |event| sse_event(&id, &event))
+        .map_err(|()| io::Error::from(io::ErrorKind::BrokenPipe));
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        // stop nginx from buffering the stream.
+        .header("X-Accel-Buffering", "no")
+        .body(Body::wrap_stream(body))?;
+    Ok(response)
+}
+
+fn sse_event(id: &str, event: &JobEvent) -> String {
+    let (name, data) = match event {
+        JobEvent::Status(status) => ("status", json!({ "id": id, "status": status.name() })),
+        JobEvent::Progress(percent) => ("progress", json!({ "id": id, "progress": percent })),
+        JobEvent::Done => (
+            "done",
+            json!({ "id": id, "result_url": format!("/jobs/{}/result", id) }),
+        ),
+        JobEvent::Failed(error) => ("failed", json!({ "id": id, "error": error })),
+    };
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+fn metrics() -> Result<Response<Body>> {
+    let mut body = METRICS.render();
+    let (hits, misses) = token_cache_stats();
+    write_metric(
+        &mut body,
+        "ytdl_token_cache_hits_total",
+        "The player tokens found in the cache.",
+        "counter",
+        hits,
+    );
+    write_metric(
+        &mut body,
+        "ytdl_token_cache_misses_total",
+        "The player tokens we had to fetch and extract.",
+        "counter",
+        misses,
+    );
+    write_metric(
+        &mut body,
+        "ytdl_ffmpeg_running",
+        "The ffmpeg runs in flight.",
+        "gauge",
+        FFMPEG_POOL.running(),
+    );
+    write_metric(
+        &mut body,
+        "ytdl_jobs_queued",
+        "The jobs waiting for an ffmpeg slot.",
+        "gauge",
+        JOBS.count(JobStatus::Queued),
+    );
+    write_metric(
+        &mut body,
+        "ytdl_jobs_running",
+        "The jobs being extracted.",
+        "gauge",
+        JOBS.count(JobStatus::Running),
+    );
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
         .body(Body::from(body))?;
     Ok(response)
 }
 
-fn extract_gif(req: &Request<Body>) -> Result<Response<Body>> {
-    let query = req
-        .uri()
-        .query()
-        .ok_or_else(|| err_msg("Expected to have query string in url"))?;
-    let hash_query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
-        .into_owned()
-        .collect();
-    let video_url = validate_query(&hash_query, "url")?;
-    if !GOOGLE_VIDEO_URL_REGEX.is_match(video_url) {
-        Err(err_msg(
-            "Maybe not a youtube video url? it should be from googlevideo.com",
+fn extract_clip(req: &Request<Body>) -> HandlerFuture {
+    let clip = parse_query(req).and_then(|query| parse_clip(&query));
+    let (video_url, start, duration, options) = match clip {
+        Ok(clip) => clip,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    let caller = req.extensions().get::<Caller>();
+    let seconds = duration.ceil_secs();
+    if let Err(denied) = AUTH.charge_clip(caller, seconds) {
+        return Box::new(future::err(denied.into()));
+    }
+    let format = options.format;
+    let cancel = Cancel::new();
+    let job_cancel = cancel.clone();
+    let job_caller = caller.cloned();
+    let job = FFMPEG_POOL.try_spawn(move || {
+        let limits = CONFIG.ffmpeg.limits();
+        make_clip(
+            &video_url,
+            &start,
+            &duration,
+            &options,
+            &limits,
+            &job_cancel,
+        )
+        .inspect_err(|_| {
+            // the clip was not made, nor sent if the client went away.
+            AUTH.refund_clip(job_caller.as_ref(), seconds)
+        })
+    });
+    let job = match job {
+        Some(job) => job,
+        None => {
+            AUTH.refund_clip(caller, seconds);
+            return Box::new(future::result(service_unavailable(WORKERS_BUSY)));
+        }
+    };
+    let response = CancelOnDrop::new(job, cancel).and_then(move |body| {
+        let disposition = format!(r#"inline; filename="extracted.{}""#, format.extension());
+        let response = Response::builder()
+            .header("Content-Type", format.content_type())
+            .header("Content-Disposition", disposition.as_str())
+            .status(StatusCode::OK)
+            .body(Body::from(body))?;
+        Ok(response)
+    });
+    Box::new(response)
+}
+
+// Check the /extract query, returns the video url, the start, the duration and
+// the encoding options of the clip.
+fn parse_clip(
+    hash_query: &HashMap<String, String>,
+) -> Result<(String, Timestamp, Timestamp, ClipOptions)> {
+    let video_url = validate_video_url(hash_query)?;
+    let start_time = validate_query(hash_query, "start")?;
+    let end_time = validate_query(hash_query, "end")?;
+    let start = Timestamp::from(start_time.as_str())?;
+    let end = Timestamp::from(end_time.as_str())?;
+    let duration = start.until(end)?;
+    if duration >= Timestamp::from_secs(CONFIG.clips.max_seconds) {
+        Err(ApiError::new(
+            ErrorCode::ClipTooLong,
+            format!(
+                "The difference between start and end, should be less than {} seconds, sorry !",
+                CONFIG.clips.max_seconds
+            ),
         ))?
     }
-    let start_time = validate_query(&hash_query, "start")?;
-    let end_time = validate_query(&hash_query, "end")?;
-    let start = HumanTime::from(start_time.as_str())?;
-    let end = HumanTime::from(end_time.as_str())?;
-    let duration = start.calculate_duration(end)?;
-    if duration.0 >= 1 || duration.1 >= 1 {
-        Err(err_msg(
-            "The difference between start and end, should be less than 1 min, sorry !",
+    let options = parse_clip_options(hash_query)?;
+    Ok((video_url, start, duration, options))
+}
+
+fn validate_video_url(query: &HashMap<String, String>) -> Result<String> {
+    let video_url = validate_query(query, "url")?;
+    if !is_allowed_url(video_url) {
+        Err(ApiError::new(
+            ErrorCode::InvalidUrl,
+            format!(
+                "Maybe not a youtube video url? it should be from {}",
+                CONFIG.upstream.allowed_hosts.join(", ")
+            ),
         ))?
     }
-    let body = make_gif(&video_url, &start, &duration)?;
-    let response = Response::builder()
-        .header("Content-Type", "image/gif")
-        .header("Content-Disposition", r#"inline; filename="extracted.gif""#)
-        .status(StatusCode::OK)
-        .body(Body::from(body))?;
-    Ok(response)
+    Ok(video_url.clone())
+}
+
+// Whether `url` is http(s) on one of the allowed hosts or their subdomains,
+// with one of the allowed paths.
+fn is_allowed_url(url: &str) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    let host = match url.host_str() {
+        Some(host) if url.scheme() == "http" || url.scheme() == "https" => host,
+        _ => return false,
+    };
+    let upstream = &CONFIG.upstream;
+    upstream.allowed_hosts.iter().any(|allowed| {
+        host.eq_ignore_ascii_case(allowed)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", allowed.to_ascii_lowercase()))
+    }) && upstream
+        .allowed_paths
+        .iter()
+        .any(|allowed| url.path().starts_with(allowed.as_str()))
+}
+
+fn extract_frame(req: &Request<Body>) -> HandlerFuture {
+    let frame = parse_query(req).and_then(|query| {
+        let time = Timestamp::from(validate_query(&query, "time")?.as_str())?;
+        let frames = &CONFIG.frames;
+        let width = match query.get("width") {
+            Some(width) => parse_bounded(width, "width", frames.min_width, frames.max_width)?,
+            None => frames.width,
+        };
+        let format = match query.get("format") {
+            Some(format) => FrameFormat::from(format)?,
+            None => frames.format,
+        };
+        Ok((frame_video_url(&query)?, time, width, format))
+    });
+    let (video_url, time, width, format) = match frame {
+        Ok(frame) => frame,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    let response = video_url.and_then(move |video_url| -> HandlerFuture {
+        let cancel = Cancel::new();
+        let job_cancel = cancel.clone();
+        let job = FFMPEG_POOL.try_spawn(move || {
+            let limits = CONFIG.ffmpeg.limits();
+            make_frame(&video_url, &time, width, format, &limits, &job_cancel)
+        });
+        let job = match job {
+            Some(job) => job,
+            None => return Box::new(future::result(service_unavailable(WORKERS_BUSY))),
+        };
+        let response = CancelOnDrop::new(job, cancel).and_then(move |body| {
+            let disposition = format!(r#"inline; filename="frame.{}""#, format.extension());
+            let response = Response::builder()
+                .header("Content-Type", format.content_type())
+                .header("Content-Disposition", disposition.as_str())
+                .status(StatusCode::OK)
+                .body(Body::from(body))?;
+            Ok(response)
+        });
+        Box::new(response)
+    });
+    Box::new(response)
+}
+
+// The video to grab a frame from, the given url or the first source of the video id.
+fn frame_video_url(
+    query: &HashMap<String, String>,
+) -> Result<Box<dyn Future<Item = String, Error = Error> + Send>> {
+    if query.contains_key("url") || !query.contains_key("v") {
+        return Ok(Box::new(future::ok(validate_video_url(query)?)));
+    }
+    let video_id = validate_query(query, "v")?;
+    let video_url = WATCH_CACHE.video(video_id).and_then(|video| {
+        video.sources.iter().find_map(source_url).ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::VideoUnavailable,
+                "Ops, this video has no sources.",
+            )
+            .into()
+        })
+    });
+    Ok(Box::new(video_url))
 }
 
-fn make_gif<'b>(url: &str, start: &HumanTime, duration: &HumanTime) -> Result<Vec<u8>> {
-    let start_time: &str = &start.to_string();
-    let duration_str: &str = &duration.to_string();
-    let command = Command::new("ffmpeg")
-        .args(&["-v", "error"])
-        .args(&["-ss", start_time])
-        .args(&["-t", duration_str])
-        .args(&["-i", url])
-        .args(&["-f", "gif"])
-        .args(&["-preset", "superfast"])
-        .arg("-hide_banner")
-        .args(&["-vf", "scale=340:-1"])
-        .arg("pipe:1")
-        .output()?;
-    if command.status.success() {
-        return Ok(command.stdout);
-    } else {
-        let err = String::from_utf8_lossy(&command.stderr);
-        print!("Err: {}\n", err);
-        Err(err_msg(
-            r"Error While Making the gif, maybe a bad url ? or missing signture !
-            and oh, please make sure that the url is encoded correctly",
+fn parse_clip_options(query: &HashMap<String, String>) -> Result<ClipOptions> {
+    let clips = &CONFIG.clips;
+    let mut options = ClipOptions {
+        format: clips.format,
+        width: clips.width,
+        quality: clips.quality,
+        ..ClipOptions::default()
+    };
+    if let Some(format) = query.get("format") {
+        options.format = ClipFormat::from(format)?;
+    }
+    if let Some(width) = query.get("width") {
+        options.width = parse_bounded(width, "width", clips.min_width, clips.max_width)?;
+    }
+    if let Some(fps) = query.get("fps") {
+        options.fps = Some(parse_bounded(fps, "fps", 1, clips.max_fps)?);
+    }
+    if let Some(quality) = query.get("quality") {
+        options.quality = parse_bounded(quality, "quality", 1, 100)? as u8;
+    }
+    options.palette = parse_gif_palette(query)?;
+    if options.palette.is_some() && options.format != ClipFormat::Gif {
+        Err(ApiError::new(
+            ErrorCode::InvalidParam,
+            "The palette options only work with the gif format",
         ))?
     }
-    Ok(b"".to_vec())
+    Ok(options)
+}
+
+// The two-pass palette mode is on with `palette=true` or any of its options.
+fn parse_gif_palette(query: &HashMap<String, String>) -> Result<Option<GifPalette>> {
+    let enabled = match query.get("palette").map(String::as_str) {
+        Some("true") | Some("1") => true,
+        Some("false") | Some("0") => return Ok(None),
+        Some(_) => Err(ApiError::new(
+            ErrorCode::InvalidParam,
+            "'palette' should be true or false",
+        ))?,
+        None => ["dither", "max_colors", "stats_mode"]
+            .iter()
+            .any(|key| query.contains_key(*key)),
+    };
+    if !enabled {
+        return Ok(None);
+    }
+    let mut palette = GifPalette::default();
+    if let Some(dither) = query.get("dither") {
+        palette.dither = Dither::from(dither)?;
+    }
+    if let Some(colors) = query.get("max_colors") {
+        palette.max_colors = parse_bounded(colors, "max_colors", 2, 256)? as u16;
+    }
+    if let Some(mode) = query.get("stats_mode") {
+        palette.stats_mode = StatsMode::from(mode)?;
+    }
+    Ok(Some(palette))
+}
+
+fn parse_bounded(value: &str, key: &str, min: u32, max: u32) -> Result<u32> {
+    match value.parse() {
+        Ok(number) if number >= min && number <= max => Ok(number),
+        _ => Err(ApiError::new(
+            ErrorCode::InvalidParam,
+            format!("'{}' should be a number between {} and {}", key, min, max),
+        ))?,
+    }
 }
-/// Look up our server port number in PORT, for compatibility with Heroku.
-fn get_server_port() -> u16 {
-    env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080)
+
+fn service_unavailable(message: &str) -> Result<Response<Body>> {
+    let mut response = error_response(ErrorCode::Busy, message);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(BUSY_RETRY_AFTER));
+    Ok(response)
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
-    let port = get_server_port();
-    let addr = ([0, 0, 0, 0], port).into();
-    println!("Starting Server..");
+    logging::init()?;
+    lazy_static::initialize(&CONFIG);
+    set_ffmpeg_path(&CONFIG.ffmpeg.path);
+    lazy_static::initialize(&AUTH);
+    if !AUTH.is_enabled() {
+        warn!("No API keys, anyone can use the server");
+    }
+    let addr = CONFIG.server.bind;
+    info!("Starting Server..");
     let server = Server::bind(&addr)
-        .serve(|| service_fn(router))
-        .map_err(|e| eprintln!("Server error: {}", e));
+        // notice the clients hanging up mid request, so their ffmpeg is killed.
+        .http1_half_close(false)
+        .serve(make_service_fn(|socket: &AddrStream| {
+            let remote = socket.remote_addr().ip();
+            service_fn(move |req| handle(req, remote))
+        }))
+        .map_err(|e| error!("Server error: {}", e));
 
-    println!("Listening on http://{}", addr);
+    info!("Listening on http://{}", addr);
     hyper::rt::run(server);
     Ok(())
 }
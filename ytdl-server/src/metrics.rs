@@ -0,0 +1,163 @@
+use failure::Error;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use ytdl_lib::error::{FetchError, VideoError};
+
+// the upper bounds of the histogram buckets, in seconds.
+const REQUEST_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const FFMPEG_BUCKETS: [f64; 9] = [0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    // not cumulative, summed when rendered.
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// The counters and histograms served at /metrics, in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // by route and status.
+    requests: Mutex<BTreeMap<(&'static str, u16), Histogram>>,
+    // by outcome, success or failure.
+    ffmpeg_runs: Mutex<BTreeMap<&'static str, Histogram>>,
+    // by error type.
+    upstream_errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn observe_request(&self, route: &'static str, status: u16, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        requests
+            .entry((route, status))
+            .or_insert_with(|| Histogram::new(&REQUEST_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_ffmpeg(&self, success: bool, elapsed: Duration) {
+        let outcome = if success { "success" } else { "failure" };
+        let mut runs = self.ffmpeg_runs.lock().unwrap();
+        runs.entry(outcome)
+            .or_insert_with(|| Histogram::new(&FFMPEG_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count an error we got from youtube, by its type.
+    pub fn upstream_error(&self, error: &Error) {
+        let kind = match (
+            error.downcast_ref::<FetchError>(),
+            error.downcast_ref::<VideoError>(),
+        ) {
+            (Some(FetchError::Status { status, .. }), _) => format!("status_{}", status),
+            (Some(FetchError::RetriesExhausted { .. }), _) => "retries_exhausted".to_string(),
+            (_, Some(VideoError::AgeRestricted { .. })) => "age_restricted".to_string(),
+            (_, Some(VideoError::Private { .. })) => "private".to_string(),
+            (_, Some(VideoError::Unavailable { .. })) => "unavailable".to_string(),
+            _ => "other".to_string(),
+        };
+        *self
+            .upstream_errors
+            .lock()
+            .unwrap()
+            .entry(kind)
+            .or_insert(0) += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "ytdl_http_request_duration_seconds",
+            "The time to answer a request, until its headers.",
+            "histogram",
+        );
+        for ((route, status), histogram) in self.requests.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",status=\"{}\"", route, status);
+            histogram.render(&mut out, "ytdl_http_request_duration_seconds", &labels);
+        }
+        header(
+            &mut out,
+            "ytdl_ffmpeg_run_duration_seconds",
+            "The time ffmpeg took, by outcome.",
+            "histogram",
+        );
+        for (outcome, histogram) in self.ffmpeg_runs.lock().unwrap().iter() {
+            let labels = format!("outcome=\"{}\"", outcome);
+            histogram.render(&mut out, "ytdl_ffmpeg_run_duration_seconds", &labels);
+        }
+        header(
+            &mut out,
+            "ytdl_upstream_errors_total",
+            "The errors we got from youtube, by type.",
+            "counter",
+        );
+        for (kind, count) in self.upstream_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "ytdl_upstream_errors_total{{type=\"{}\"}} {}",
+                kind, count
+            );
+        }
+        out
+    }
+}
+
+/// Add a metric without labels to `out`.
+pub fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: usize) {
+    header(out, name, help, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
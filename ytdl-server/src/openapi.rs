@@ -0,0 +1,368 @@
+use crate::error::ErrorCode;
+use crate::routes::{Kind, Location, Param, Route};
+use hyper::Method;
+use serde_json::{json, Map, Value};
+
+/// The OpenAPI 3 description of `routes`, served at `/openapi.json`.
+pub fn document(routes: &[Route]) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let item = paths
+            .entry(route.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[route.method.as_str().to_lowercase()] = operation(route);
+    }
+    json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "ytdl-server",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Get the sources of youtube videos, download them and extract clips. \
+                            The API keys are only needed when the server has some."
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "ApiKeyHeader": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+                "ApiKeyQuery": { "type": "apiKey", "in": "query", "name": "api_key" }
+            }
+        }
+    })
+}
+
+/// The endpoints served at `/`, a short version of the OpenAPI document.
+pub fn help(routes: &[Route]) -> Value {
+    let endpoints = routes
+        .iter()
+        .map(|route| {
+            let describe = |required: bool| {
+                route
+                    .params
+                    .iter()
+                    .filter(|param| param.required == required)
+                    .map(|param| (param.name.to_string(), json!(param.description)))
+                    .collect::<Map<_, _>>()
+            };
+            let mut endpoint = json!({
+                "path": route.path,
+                "method": route.method.as_str(),
+                "required_params": describe(true),
+                "description": route.summary,
+            });
+            let optional = describe(false);
+            if !optional.is_empty() {
+                endpoint["optional_params"] = Value::Object(optional);
+            }
+            endpoint
+        })
+        .collect::<Vec<_>>();
+    json!({ "endpoints": endpoints })
+}
+
+fn operation(route: &Route) -> Value {
+    // the forms take the query params in their body too.
+    let in_body = route.method == Method::POST;
+    let parameters = route
+        .params
+        .iter()
+        .filter(|param| !in_body || param.location == Location::Path)
+        .map(|param| {
+            json!({
+                "name": param.name,
+                "in": match param.location {
+                    Location::Query => "query",
+                    Location::Path => "path",
+                },
+                "required": param.required || param.location == Location::Path,
+                "description": param.description,
+                "schema": param_schema(param),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut responses = Map::new();
+    for reply in &route.replies {
+        let content = reply
+            .content_types
+            .iter()
+            .map(|content_type| {
+                let schema = match reply.schema {
+                    Some(schema) => json!({ "$ref": format!("#/components/schemas/{}", schema) }),
+                    None => json!({ "type": "string", "format": "binary" }),
+                };
+                (content_type.to_string(), json!({ "schema": schema }))
+            })
+            .collect::<Map<_, _>>();
+        responses.insert(
+            reply.status.to_string(),
+            json!({ "description": reply.description, "content": content }),
+        );
+    }
+    let mut errors = vec![
+        ("429", "Too many requests, see the Retry-After header"),
+        ("500", "The request failed"),
+    ];
+    if route
+        .params
+        .iter()
+        .any(|param| param.location == Location::Query)
+    {
+        errors.push(("400", "A param is missing or invalid"));
+    }
+    if !route.public {
+        errors.push(("401", "Missing or unknown API key"));
+        errors.push(("403", "The API key can't use this endpoint"));
+    }
+    for (status, description) in errors {
+        responses.entry(status).or_insert_with(|| {
+            json!({
+                "description": description,
+                "content": {
+                    "application/json": {
+                        "schema": { "$ref": "#/components/schemas/Error" }
+                    }
+                }
+            })
+        });
+    }
+    let mut operation = json!({
+        "operationId": format!("{:?}", route.endpoint),
+        "summary": route.summary,
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if in_body {
+        operation["requestBody"] = form_body(route);
+    }
+    if !route.public {
+        operation["security"] = json!([{ "ApiKeyHeader": [] }, { "ApiKeyQuery": [] }]);
+    }
+    operation
+}
+
+fn form_body(route: &Route) -> Value {
+    let params = route
+        .params
+        .iter()
+        .filter(|param| param.location == Location::Query);
+    let properties = params
+        .clone()
+        .map(|param| {
+            let mut schema = param_schema(param);
+            schema["description"] = json!(param.description);
+            (param.name.to_string(), schema)
+        })
+        .collect::<Map<_, _>>();
+    let required = params
+        .filter(|param| param.required)
+        .map(|param| param.name)
+        .collect::<Vec<_>>();
+    json!({
+        "description": "The params, here or in the query string",
+        "content": {
+            "application/x-www-form-urlencoded": {
+                "schema": {
+                    "type": "object",
+                    "properties": properties,
+                    "required": required
+                }
+            }
+        }
+    })
+}
+
+fn param_schema(param: &Param) -> Value {
+    let kind = match param.kind {
+        Kind::String => "string",
+        Kind::Integer => "integer",
+        Kind::Boolean => "boolean",
+    };
+    let mut schema = json!({ "type": kind });
+    if !param.values.is_empty() {
+        schema["enum"] = json!(param.values);
+    }
+    schema
+}
+
+fn schemas() -> Value {
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["error", "code"],
+            "properties": {
+                "error": { "type": "string", "description": "For humans" },
+                "code": {
+                    "type": "string",
+                    "description": "Stable, for the programs to tell the errors apart",
+                    "enum": ErrorCode::ALL.iter().map(|code| code.name()).collect::<Vec<_>>()
+                }
+            }
+        },
+        "Help": {
+            "type": "object",
+            "properties": {
+                "endpoints": { "type": "array", "items": { "type": "object" } }
+            }
+        },
+        "OpenApi": { "type": "object" },
+        "VideoSources": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "description": "A format of the video, with the youtube names like itag, url, \
+                                type or quality",
+                "additionalProperties": { "type": "string" }
+            }
+        },
+        "JobCreated": {
+            "type": "object",
+            "required": ["id", "status", "status_url"],
+            "properties": {
+                "id": { "type": "string" },
+                "status": { "type": "string", "enum": ["queued"] },
+                "status_url": { "type": "string" }
+            }
+        },
+        "Job": {
+            "type": "object",
+            "required": ["id", "status", "progress"],
+            "properties": {
+                "id": { "type": "string" },
+                "status": { "type": "string", "enum": ["queued", "running", "done", "failed"] },
+                "progress": { "type": "integer", "minimum": 0, "maximum": 100 },
+                "error": { "type": "string", "description": "When failed" },
+                "result_url": { "type": "string", "description": "When done" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::jobs::JobStore;
+    use crate::routes::{find, routes, Endpoint};
+    use ytdl_lib::clip::ClipFormat;
+
+    // Enough of JSON Schema for the flat objects we answer.
+    fn assert_matches(value: &Value, schema: &Value) {
+        for key in schema["required"].as_array().unwrap() {
+            let key = key.as_str().unwrap();
+            assert!(
+                value.get(key).is_some(),
+                "{} is missing from {}",
+                key,
+                value
+            );
+        }
+        for (key, field) in value.as_object().unwrap() {
+            let property = &schema["properties"][key];
+            assert!(property.is_object(), "{} is not documented", key);
+            match property["type"].as_str().unwrap() {
+                "integer" => {
+                    let number = field
+                        .as_i64()
+                        .unwrap_or_else(|| panic!("{} = {}", key, field));
+                    if let Some(minimum) = property["minimum"].as_i64() {
+                        assert!(number >= minimum, "{} = {}", key, number);
+                    }
+                    if let Some(maximum) = property["maximum"].as_i64() {
+                        assert!(number <= maximum, "{} = {}", key, number);
+                    }
+                }
+                "string" => {
+                    assert!(field.is_string(), "{} = {}", key, field);
+                    if let Some(values) = property["enum"].as_array() {
+                        assert!(values.contains(field), "{} = {}", key, field);
+                    }
+                }
+                other => panic!("no check for {}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn job_status_matches_its_schema() {
+        let schema = &schemas()["Job"];
+        let store = JobStore::new(10, 1024);
+        let running = store.create(ClipFormat::Gif).unwrap();
+        store.set_running(&running);
+        store.set_progress(&running, 0.426);
+        let job = store.get(&running).unwrap();
+        assert_eq!(job.to_json()["progress"], 43);
+        assert_matches(&job.to_json(), schema);
+        let done = store.create(ClipFormat::Gif).unwrap();
+        store.finish(&done, Ok(vec![1, 2, 3]));
+        let job = store.get(&done).unwrap().to_json();
+        assert_eq!(job["progress"], 100);
+        assert_matches(&job, schema);
+        let failed = store.create(ClipFormat::Gif).unwrap();
+        store.finish(&failed, Err("ffmpeg failed making a gif".to_string()));
+        assert_matches(&store.get(&failed).unwrap().to_json(), schema);
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let routes = routes(&Config::default());
+        let document = document(&routes);
+        let schemas = &document["components"]["schemas"];
+        for endpoint in Endpoint::all() {
+            let route = routes
+                .iter()
+                .find(|route| route.endpoint == endpoint)
+                .unwrap_or_else(|| panic!("{:?} has no route", endpoint));
+            let method = route.method.as_str().to_lowercase();
+            let operation = &document["paths"][route.path][&method];
+            assert!(
+                operation.is_object(),
+                "{} {} is not documented",
+                route.method,
+                route.path
+            );
+            let responses = operation["responses"].as_object().unwrap();
+            assert!(
+                responses.keys().any(|status| status.starts_with('2')),
+                "{} {} has no success response",
+                route.method,
+                route.path
+            );
+            for response in responses.values() {
+                for content in response["content"].as_object().unwrap().values() {
+                    if let Some(reference) = content["schema"]["$ref"].as_str() {
+                        let name = reference.trim_start_matches("#/components/schemas/");
+                        assert!(schemas[name].is_object(), "No schema {}", name);
+                    }
+                }
+            }
+            let parameters = operation["parameters"].as_array().unwrap();
+            for segment in route.path.split('/').filter(|s| s.starts_with('{')) {
+                let name = segment.trim_matches(|c| c == '{' || c == '}');
+                assert!(
+                    parameters
+                        .iter()
+                        .any(|param| param["name"] == name && param["in"] == "path"),
+                    "{} {} doesn't document {}",
+                    route.method,
+                    route.path,
+                    segment
+                );
+            }
+            // the router finds the route back from a real path.
+            let path = route.path.replace("{id}", "0123abcd");
+            let found = find(&routes, &route.method, &path).map(|route| route.endpoint);
+            assert_eq!(found, Some(endpoint), "{} {}", route.method, path);
+        }
+        assert_eq!(routes.len(), Endpoint::all().len());
+    }
+
+    #[test]
+    fn help_lists_every_route() {
+        let routes = routes(&Config::default());
+        let help = help(&routes);
+        assert_eq!(
+            help["endpoints"].as_array().map(Vec::len),
+            Some(routes.len())
+        );
+    }
+}
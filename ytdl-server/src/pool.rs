@@ -0,0 +1,307 @@
+use crate::logging;
+use crate::metrics::METRICS;
+use failure::Error;
+use futures::{Async, Future, Poll, Stream};
+use futures_cpupool::{CpuFuture, CpuPool};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use ytdl_lib::ffmpeg::Cancel;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A thread pool for the blocking work (mostly ffmpeg), so it never runs on the
+/// reactor threads. At most `limit` jobs run at once, the extra requests are
+/// refused with `try_spawn` or queued with `try_queue`. The queued jobs only
+/// reach the pool once they hold a slot, so a request granted one by
+/// `try_spawn` never waits behind them. Jobs log with the id of the request
+/// that spawned them.
+pub struct JobPool {
+    slots: Arc<Slots>,
+    limit: usize,
+    max_queued: usize,
+}
+
+type Queued = Box<dyn FnOnce(Slot) + Send>;
+
+struct Slots {
+    pool: CpuPool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    queue: VecDeque<Queued>,
+}
+
+// A taken slot, once dropped it goes to the next queued job or is freed, even
+// if the job panicked.
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(next) => {
+                let slot = Slot(self.0.clone());
+                self.0
+                    .pool
+                    .spawn_fn(move || -> std::result::Result<(), ()> {
+                        next(slot);
+                        Ok(())
+                    })
+                    .forget();
+            }
+            None => state.running -= 1,
+        }
+    }
+}
+
+impl JobPool {
+    pub fn new(limit: usize, max_queued: usize) -> Self {
+        let limit = limit.max(1);
+        JobPool {
+            slots: Arc::new(Slots {
+                pool: CpuPool::new(limit),
+                state: Mutex::new(State::default()),
+            }),
+            limit,
+            max_queued,
+        }
+    }
+
+    /// Run `job` on the pool, `None` when all the slots are taken.
+    pub fn try_spawn<F, T>(&self, job: F) -> Option<CpuFuture<T, Error>>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = {
+            let mut state = self.slots.state.lock().unwrap();
+            if state.running >= self.limit {
+                return None;
+            }
+            state.running += 1;
+            Slot(self.slots.clone())
+        };
+        let request_id = logging::current_request_id();
+        let job = self.slots.pool.spawn_fn(move || {
+            let _slot = slot;
+            logging::scope(request_id, || timed(job))
+        });
+        Some(job)
+    }
+
+    /// Run `job` once a slot frees up, `started` is called when it runs. False
+    /// when the queue is full.
+    pub fn try_queue<F, S>(&self, started: S, job: F) -> bool
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+        S: FnOnce() + Send + 'static,
+    {
+        let request_id = logging::current_request_id();
+        let run: Queued = Box::new(move |slot| {
+            let _slot = slot;
+            logging::scope(request_id, || {
+                started();
+                // the job reports its result itself.
+                let _ = timed(job);
+            })
+        });
+        let mut state = self.slots.state.lock().unwrap();
+        if state.running < self.limit {
+            state.running += 1;
+            let slot = Slot(self.slots.clone());
+            self.slots
+                .pool
+                .spawn_fn(move || -> std::result::Result<(), ()> {
+                    run(slot);
+                    Ok(())
+                })
+                .forget();
+        } else if state.queue.len() < self.max_queued {
+            state.queue.push_back(run);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// The number of jobs running right now.
+    pub fn running(&self) -> usize {
+        self.slots.state.lock().unwrap().running
+    }
+}
+
+/// Cancels the ffmpeg run of a job when dropped before its future or stream
+/// is done, so it is killed when nobody waits on its result or its body
+/// anymore, like when the client hung up.
+pub struct CancelOnDrop<F> {
+    inner: F,
+    cancel: Cancel,
+    done: bool,
+}
+
+impl<F> CancelOnDrop<F> {
+    pub fn new(inner: F, cancel: Cancel) -> Self {
+        CancelOnDrop {
+            inner,
+            cancel,
+            done: false,
+        }
+    }
+}
+
+impl<F: Future> Future for CancelOnDrop<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let poll = self.inner.poll();
+        self.done = !matches!(poll, Ok(Async::NotReady));
+        poll
+    }
+}
+
+impl<S: Stream> Stream for CancelOnDrop<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let poll = self.inner.poll();
+        self.done = matches!(poll, Ok(Async::Ready(None)));
+        poll
+    }
+}
+
+impl<F> Drop for CancelOnDrop<F> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cancel.cancel();
+        }
+    }
+}
+
+// Run a job, recording how long ffmpeg took and whether it failed.
+fn timed<F, T>(job: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let started = Instant::now();
+    let result = job();
+    METRICS.observe_ffmpeg(result.is_ok(), started.elapsed());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, stream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn wait_until_idle(pool: &JobPool) {
+        let started = Instant::now();
+        while pool.running() > 0 {
+            assert!(started.elapsed() < WAIT, "the slots were never freed");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn queued_jobs_wait_for_a_slot() {
+        let pool = JobPool::new(1, 1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let first = pool
+            .try_spawn(move || {
+                blocked.recv().ok();
+                Ok(())
+            })
+            .unwrap();
+        assert!(pool.try_spawn(|| Ok(())).is_none());
+        let (done, finished) = mpsc::channel();
+        assert!(pool.try_queue(
+            || {},
+            move || {
+                done.send(()).unwrap();
+                Ok(())
+            }
+        ));
+        // the queue is full too.
+        assert!(!pool.try_queue(|| {}, || Ok(())));
+        assert_eq!(pool.running(), 1);
+        release.send(()).unwrap();
+        first.wait().unwrap();
+        finished.recv_timeout(WAIT).unwrap();
+        wait_until_idle(&pool);
+        assert!(pool.try_spawn(|| Ok(())).is_some());
+    }
+
+    #[test]
+    fn queued_jobs_take_no_more_than_the_limit() {
+        let pool = JobPool::new(2, 8);
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        for _ in 0..4 {
+            let blocked = blocked.clone();
+            assert!(pool.try_queue(
+                || {},
+                move || {
+                    blocked.lock().unwrap().recv().ok();
+                    Ok(())
+                }
+            ));
+        }
+        assert_eq!(pool.running(), 2);
+        // the requests are refused at once, never queued behind the jobs.
+        assert!(pool.try_spawn(|| Ok(())).is_none());
+        for _ in 0..4 {
+            release.send(()).unwrap();
+        }
+        wait_until_idle(&pool);
+    }
+
+    #[test]
+    fn requests_are_not_queued_behind_the_jobs() {
+        let pool = JobPool::new(2, 8);
+        let (release, blocked) = mpsc::channel::<()>();
+        assert!(pool.try_queue(
+            || {},
+            move || {
+                blocked.recv().ok();
+                Ok(())
+            }
+        ));
+        let request = pool.try_spawn(|| Ok(42)).unwrap();
+        assert_eq!(request.wait().unwrap(), 42);
+        release.send(()).unwrap();
+        wait_until_idle(&pool);
+    }
+
+    #[test]
+    fn cancels_only_what_was_left_unfinished() {
+        let cancel = Cancel::new();
+        drop(CancelOnDrop::new(future::empty::<(), ()>(), cancel.clone()));
+        assert!(cancel.is_cancelled());
+
+        let cancel = Cancel::new();
+        let done = CancelOnDrop::new(future::ok::<_, ()>(1), cancel.clone());
+        assert_eq!(done.wait(), Ok(1));
+        assert!(!cancel.is_cancelled());
+
+        let cancel = Cancel::new();
+        let mut body = CancelOnDrop::new(stream::iter_ok::<_, ()>(vec![1, 2]), cancel.clone());
+        assert_eq!(body.by_ref().take(1).collect().wait(), Ok(vec![1]));
+        drop(body);
+        assert!(cancel.is_cancelled());
+
+        let cancel = Cancel::new();
+        let body = CancelOnDrop::new(stream::iter_ok::<_, ()>(vec![1, 2]), cancel.clone());
+        assert_eq!(body.collect().wait(), Ok(vec![1, 2]));
+        assert!(!cancel.is_cancelled());
+    }
+}
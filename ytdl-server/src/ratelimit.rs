@@ -0,0 +1,235 @@
+use hyper::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// the most buckets we track, past it the least recently used tenth is dropped.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+// how often the buckets that are full again are forgotten.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The endpoints are limited apart, ffmpeg ones cost much more than the others.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Bucket {
+    Cheap,
+    Expensive,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What the limiter decided for a request, with the `RateLimit-*` headers to send back.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub allowed: bool,
+    limit: u32,
+    remaining: u32,
+    // seconds until the bucket is full again.
+    reset: u64,
+    // seconds until the next token, when refused.
+    retry_after: u64,
+}
+
+impl Decision {
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("RateLimit-Reset", HeaderValue::from(self.reset));
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.retry_after));
+        }
+    }
+}
+
+/// Token buckets by client and endpoint kind, each one holds a minute worth of
+/// requests and refills continuously.
+#[derive(Debug)]
+pub struct RateLimiter {
+    cheap_per_minute: u32,
+    expensive_per_minute: u32,
+    max_buckets: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    buckets: HashMap<(String, Bucket), TokenBucket>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    /// A rate of 0 disables the limit of its bucket.
+    pub fn new(cheap_per_minute: u32, expensive_per_minute: u32) -> Self {
+        RateLimiter {
+            cheap_per_minute,
+            expensive_per_minute,
+            max_buckets: MAX_TRACKED_BUCKETS,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn per_minute(&self, bucket: Bucket) -> u32 {
+        match bucket {
+            Bucket::Cheap => self.cheap_per_minute,
+            Bucket::Expensive => self.expensive_per_minute,
+        }
+    }
+
+    /// Take a token from the bucket of `client`, `None` when the bucket has no limit.
+    pub fn take(&self, client: &str, bucket: Bucket) -> Option<Decision> {
+        let per_minute = self.per_minute(bucket);
+        if per_minute == 0 {
+            return None;
+        }
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let key = (client.to_string(), bucket);
+        if !state.buckets.contains_key(&key)
+            && (state.buckets.len() >= self.max_buckets
+                || now.duration_since(state.last_sweep) >= SWEEP_INTERVAL)
+        {
+            self.sweep(&mut state, now);
+        }
+        let bucket = state.buckets.entry(key).or_insert(TokenBucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Some(Decision {
+            allowed,
+            limit: per_minute,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / per_second).ceil() as u64,
+            retry_after: ((1.0 - bucket.tokens).max(0.0) / per_second).ceil() as u64,
+        })
+    }
+
+    // Forget the buckets that are full again, they would start full anyway.
+    // When still at the cap, drop the least recently used tenth so the next
+    // sweeps are far apart.
+    fn sweep(&self, state: &mut State, now: Instant) {
+        state.last_sweep = now;
+        state.buckets.retain(|(_, kind), bucket| {
+            let capacity = f64::from(self.per_minute(*kind));
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * capacity / 60.0 < capacity
+        });
+        if state.buckets.len() < self.max_buckets {
+            return;
+        }
+        let mut updated: Vec<Instant> = state.buckets.values().map(|b| b.updated).collect();
+        let count = (self.max_buckets / 10).max(1);
+        let (_, newest_dropped, _) = updated.select_nth_unstable(count - 1);
+        let newest_dropped = *newest_dropped;
+        let mut dropped = 0;
+        state.buckets.retain(|_, bucket| {
+            if dropped < count && bucket.updated <= newest_dropped {
+                dropped += 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// The address of the client, taken from `X-Forwarded-For` when the request
+/// comes through one of our `trusted` proxies.
+pub fn client_ip(headers: &HeaderMap, remote: IpAddr, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&remote) {
+        return remote;
+    }
+    let forwarded = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    // every proxy appends the address it got the request from, the first one
+    // we don't trust from the end is the client.
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|ip| !trusted.contains(ip))
+        .unwrap_or(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(cheap: u32, expensive: u32, max_buckets: usize) -> RateLimiter {
+        RateLimiter {
+            max_buckets,
+            ..RateLimiter::new(cheap, expensive)
+        }
+    }
+
+    #[test]
+    fn sweep_uses_the_rate_of_each_bucket() {
+        let limiter = limiter(60, 2, 100);
+        let now = Instant::now();
+        let mut state = limiter.state.lock().unwrap();
+        // a second refills one cheap token but only a thirtieth of an expensive one.
+        let then = now - Duration::from_secs(1);
+        for (client, bucket) in [("a", Bucket::Cheap), ("b", Bucket::Expensive)] {
+            let capacity = f64::from(limiter.per_minute(bucket));
+            state.buckets.insert(
+                (client.to_string(), bucket),
+                TokenBucket {
+                    tokens: capacity - 1.0,
+                    updated: then,
+                },
+            );
+        }
+        limiter.sweep(&mut state, now);
+        let kept: Vec<_> = state.buckets.keys().cloned().collect();
+        assert_eq!(kept, vec![("b".to_string(), Bucket::Expensive)]);
+    }
+
+    #[test]
+    fn busy_buckets_are_capped() {
+        let limiter = limiter(60, 60, 20);
+        for client in 0..100 {
+            let decision = limiter.take(&client.to_string(), Bucket::Cheap).unwrap();
+            assert!(decision.allowed);
+            assert!(limiter.state.lock().unwrap().buckets.len() <= 20);
+        }
+        // the newest clients are the ones still tracked.
+        let state = limiter.state.lock().unwrap();
+        assert!(state
+            .buckets
+            .contains_key(&("99".to_string(), Bucket::Cheap)));
+        assert!(!state
+            .buckets
+            .contains_key(&("0".to_string(), Bucket::Cheap)));
+    }
+
+    #[test]
+    fn a_client_runs_out_of_tokens() {
+        let limiter = limiter(2, 0, 100);
+        assert!(limiter.take("a", Bucket::Expensive).is_none());
+        assert!(limiter.take("a", Bucket::Cheap).unwrap().allowed);
+        assert!(limiter.take("a", Bucket::Cheap).unwrap().allowed);
+        let refused = limiter.take("a", Bucket::Cheap).unwrap();
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, 30);
+        assert!(limiter.take("b", Bucket::Cheap).unwrap().allowed);
+    }
+}
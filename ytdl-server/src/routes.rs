@@ -0,0 +1,693 @@
+use crate::config::Config;
+use crate::error::{ApiError, ErrorCode};
+use crate::ratelimit::Bucket;
+use hyper::Method;
+use std::collections::HashMap;
+
+/// What a route does, the router dispatches on it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Endpoint {
+    Help,
+    OpenApi,
+    Watch,
+    Extract,
+    Frame,
+    Download,
+    Audio,
+    Metrics,
+    SubmitJob,
+    JobStatus,
+    JobResult,
+    JobEvents,
+}
+
+impl Endpoint {
+    /// Every endpoint, each one should have a route.
+    #[cfg(test)]
+    pub fn all() -> Vec<Endpoint> {
+        let mut all = vec![Endpoint::Help];
+        while let Some(next) = all[all.len() - 1].next() {
+            all.push(next);
+        }
+        all
+    }
+
+    // The endpoints in order, the match breaks the build when one is added.
+    #[cfg(test)]
+    fn next(self) -> Option<Endpoint> {
+        match self {
+            Endpoint::Help => Some(Endpoint::OpenApi),
+            Endpoint::OpenApi => Some(Endpoint::Watch),
+            Endpoint::Watch => Some(Endpoint::Extract),
+            Endpoint::Extract => Some(Endpoint::Frame),
+            Endpoint::Frame => Some(Endpoint::Download),
+            Endpoint::Download => Some(Endpoint::Audio),
+            Endpoint::Audio => Some(Endpoint::Metrics),
+            Endpoint::Metrics => Some(Endpoint::SubmitJob),
+            Endpoint::SubmitJob => Some(Endpoint::JobStatus),
+            Endpoint::JobStatus => Some(Endpoint::JobResult),
+            Endpoint::JobResult => Some(Endpoint::JobEvents),
+            Endpoint::JobEvents => None,
+        }
+    }
+}
+
+/// Where a parameter is sent.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Location {
+    Query,
+    Path,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+    String,
+    Integer,
+    Boolean,
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: &'static str,
+    pub location: Location,
+    pub kind: Kind,
+    pub required: bool,
+    pub description: String,
+    /// The accepted values, any when empty.
+    pub values: &'static [&'static str],
+}
+
+impl Param {
+    /// A required string in the query.
+    pub fn query<D: Into<String>>(name: &'static str, description: D) -> Self {
+        Param {
+            name,
+            location: Location::Query,
+            kind: Kind::String,
+            required: true,
+            description: description.into(),
+            values: &[],
+        }
+    }
+
+    /// A segment of the path, like `{id}`.
+    pub fn path<D: Into<String>>(name: &'static str, description: D) -> Self {
+        Param {
+            location: Location::Path,
+            ..Param::query(name, description)
+        }
+    }
+
+    pub fn optional(self) -> Self {
+        Param {
+            required: false,
+            ..self
+        }
+    }
+
+    pub fn integer(self) -> Self {
+        Param {
+            kind: Kind::Integer,
+            ..self
+        }
+    }
+
+    pub fn boolean(self) -> Self {
+        Param {
+            kind: Kind::Boolean,
+            ..self
+        }
+    }
+
+    pub fn one_of(self, values: &'static [&'static str]) -> Self {
+        Param { values, ..self }
+    }
+
+    /// Check the value sent for the param, if any, against its kind and values.
+    pub fn check(&self, value: Option<&String>) -> Result<(), ApiError> {
+        let value = match value {
+            Some(value) => value,
+            None if self.required => Err(ApiError::new(
+                ErrorCode::MissingParam,
+                format!("Expected url to have query key '{}'", self.name),
+            ))?,
+            None => return Ok(()),
+        };
+        let valid = match self.kind {
+            Kind::String => true,
+            Kind::Integer => value.parse::<u64>().is_ok(),
+            Kind::Boolean => ["true", "false", "1", "0"].contains(&value.as_str()),
+        };
+        if !valid {
+            let expected = match self.kind {
+                Kind::Integer => "a whole number",
+                _ => "true or false",
+            };
+            Err(ApiError::new(
+                ErrorCode::InvalidParam,
+                format!("'{}' should be {}, not '{}'", self.name, expected, value),
+            ))?
+        }
+        if !self.values.is_empty()
+            && !self
+                .values
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(value))
+        {
+            Err(ApiError::new(
+                ErrorCode::InvalidParam,
+                format!(
+                    "Unknown {} '{}', it should be one of {}",
+                    self.name,
+                    value,
+                    self.values.join(", ")
+                ),
+            ))?
+        }
+        Ok(())
+    }
+}
+
+/// A response a route can send, the errors common to every route are added
+/// by the OpenAPI document.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub status: u16,
+    pub description: &'static str,
+    pub content_types: &'static [&'static str],
+    /// The name of the schema of a JSON body, in the OpenAPI components.
+    pub schema: Option<&'static str>,
+}
+
+impl Reply {
+    pub fn json(status: u16, description: &'static str, schema: &'static str) -> Self {
+        Reply {
+            status,
+            description,
+            content_types: &["application/json"],
+            schema: Some(schema),
+        }
+    }
+
+    /// A file or a stream, in one of `content_types`.
+    pub fn body(
+        status: u16,
+        description: &'static str,
+        content_types: &'static [&'static str],
+    ) -> Self {
+        Reply {
+            status,
+            description,
+            content_types,
+            schema: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub method: Method,
+    /// With the path parameters in braces, like `/jobs/{id}`.
+    pub path: &'static str,
+    pub endpoint: Endpoint,
+    pub summary: &'static str,
+    /// Callable without an API key.
+    pub public: bool,
+    pub bucket: Bucket,
+    pub params: Vec<Param>,
+    pub replies: Vec<Reply>,
+}
+
+impl Route {
+    fn new(method: Method, path: &'static str, endpoint: Endpoint, summary: &'static str) -> Self {
+        Route {
+            method,
+            path,
+            endpoint,
+            summary,
+            public: false,
+            bucket: Bucket::Cheap,
+            params: Vec::new(),
+            replies: Vec::new(),
+        }
+    }
+
+    fn public(self) -> Self {
+        Route {
+            public: true,
+            ..self
+        }
+    }
+
+    // the endpoints running ffmpeg take from the expensive bucket.
+    fn expensive(self) -> Self {
+        Route {
+            bucket: Bucket::Expensive,
+            ..self
+        }
+    }
+
+    fn param(mut self, param: Param) -> Self {
+        self.params.push(param);
+        self
+    }
+
+    fn reply(mut self, reply: Reply) -> Self {
+        self.replies.push(reply);
+        self
+    }
+
+    // the errors of the routes resolving a video id.
+    fn video_errors(self) -> Self {
+        self.reply(Reply::json(
+            403,
+            "The video is private or age restricted, or the API key can't use this endpoint",
+            "Error",
+        ))
+        .reply(Reply::json(
+            404,
+            "The video or the asked format doesn't exist",
+            "Error",
+        ))
+        .reply(Reply::json(502, "YouTube failed to answer", "Error"))
+    }
+
+    fn job_errors(self) -> Self {
+        self.reply(Reply::json(404, "There is no job with this id", "Error"))
+    }
+
+    /// Whether the route serves `path`, the path parameters match any segment.
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        if self.method != *method {
+            return false;
+        }
+        let mut expected = self.path.split('/');
+        let mut actual = path.split('/');
+        loop {
+            match (expected.next(), actual.next()) {
+                (None, None) => return true,
+                (Some(segment), Some(value)) if segment.starts_with('{') => {
+                    if value.is_empty() {
+                        return false;
+                    }
+                }
+                (Some(segment), Some(value)) if segment == value => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Route {
+    /// Check the query params sent to the route, the bounds are left to the
+    /// endpoint.
+    pub fn check_params(&self, query: &HashMap<String, String>) -> Result<(), ApiError> {
+        self.params
+            .iter()
+            .filter(|param| param.location == Location::Query)
+            .try_for_each(|param| param.check(query.get(param.name)))
+    }
+}
+
+/// The route serving a request, if any.
+pub fn find<'a>(routes: &'a [Route], method: &Method, path: &str) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(method, path))
+}
+
+/// Every route of the server, the bounds and defaults of the params come from `config`.
+pub fn routes(config: &Config) -> Vec<Route> {
+    let frames = &config.frames;
+    let busy = Reply::json(503, "All the ffmpeg workers are busy", "Error");
+    let too_long = Reply::json(422, "The clip is too long", "Error");
+    let timed_out = Reply::json(504, "ffmpeg took too long, it was killed", "Error");
+    vec![
+        Route::new(Method::GET, "/", Endpoint::Help, "View this endpoint.")
+            .public()
+            .reply(Reply::json(200, "The endpoints", "Help")),
+        Route::new(
+            Method::GET,
+            "/openapi.json",
+            Endpoint::OpenApi,
+            "The OpenAPI description of the server",
+        )
+        .public()
+        .reply(Reply::json(200, "An OpenAPI 3 document", "OpenApi")),
+        Route::new(
+            Method::GET,
+            "/watch",
+            Endpoint::Watch,
+            "get the video downloads urls",
+        )
+        .param(Param::query("v", "the video id"))
+        .reply(Reply::json(200, "The sources of the video", "VideoSources"))
+        .video_errors(),
+        clip_params(
+            Route::new(
+                Method::GET,
+                "/extract",
+                Endpoint::Extract,
+                "extract a gif or a short silent clip from the video",
+            )
+            .expensive(),
+            config,
+        )
+        .reply(Reply::body(
+            200,
+            "The clip, in the asked format",
+            &[
+                "image/gif",
+                "video/mp4",
+                "video/webm",
+                "image/webp",
+                "image/apng",
+            ],
+        ))
+        .reply(busy.clone())
+        .reply(Reply::json(
+            422,
+            "The clip is too long, or larger than the server allows",
+            "Error",
+        ))
+        .reply(timed_out.clone()),
+        Route::new(
+            Method::GET,
+            "/frame",
+            Endpoint::Frame,
+            "get a single frame of the video as an image",
+        )
+        .expensive()
+        .param(
+            Param::query("url", "the exteracted video url from /watch endpoint, or v").optional(),
+        )
+        .param(Param::query("v", "the video id, or url").optional())
+        .param(Param::query(
+            "time",
+            "the time of the frame, like 1:02.5, 62.5, 1m2s or 01:02:03",
+        ))
+        .param(
+            Param::query(
+                "format",
+                format!(
+                    "jpeg, png or webp, defaults to {}",
+                    frames.format.extension()
+                ),
+            )
+            .optional()
+            .one_of(&["jpeg", "jpg", "png", "webp"]),
+        )
+        .param(
+            Param::query(
+                "width",
+                format!(
+                    "the width in pixels, from {} to {}, defaults to {}",
+                    frames.min_width, frames.max_width, frames.width
+                ),
+            )
+            .optional()
+            .integer(),
+        )
+        .reply(Reply::body(
+            200,
+            "The frame, in the asked format",
+            &["image/jpeg", "image/png", "image/webp"],
+        ))
+        .reply(busy.clone())
+        .reply(timed_out)
+        .video_errors(),
+        Route::new(
+            Method::GET,
+            "/download",
+            Endpoint::Download,
+            "download a format of the video through the server, supports Range requests",
+        )
+        .param(Param::query("v", "the video id"))
+        .param(Param::query(
+            "itag",
+            "the format to download, from the /watch endpoint",
+        ))
+        .reply(Reply::body(200, "The whole file", &["video/*", "audio/*"]))
+        .reply(Reply::body(206, "The asked range", &["video/*", "audio/*"]))
+        .reply(Reply::json(
+            416,
+            "The range is past the end of the file",
+            "Error",
+        ))
+        .video_errors(),
+        Route::new(
+            Method::GET,
+            "/audio",
+            Endpoint::Audio,
+            "stream the best audio of the video",
+        )
+        .expensive()
+        .param(Param::query("v", "the video id"))
+        .param(
+            Param::query(
+                "format",
+                "original (default, m4a or webm, supports Range requests) or mp3",
+            )
+            .optional()
+            .one_of(&["original", "mp3"]),
+        )
+        .reply(Reply::body(
+            200,
+            "The audio",
+            &["audio/mp4", "audio/webm", "audio/mpeg"],
+        ))
+        .reply(Reply::body(
+            206,
+            "The asked range of the original audio",
+            &["audio/mp4", "audio/webm"],
+        ))
+        .reply(Reply::json(
+            416,
+            "The range is past the end of the file",
+            "Error",
+        ))
+        .reply(busy)
+        .video_errors(),
+        Route::new(
+            Method::GET,
+            "/metrics",
+            Endpoint::Metrics,
+            "the server metrics in the Prometheus text format",
+        )
+        .reply(Reply::body(
+            200,
+            "The metrics",
+            &["text/plain; version=0.0.4"],
+        )),
+        clip_params(
+            Route::new(
+                Method::POST,
+                "/jobs",
+                Endpoint::SubmitJob,
+                "queue an extraction, returns the job id, it takes the params of /extract \
+                 in the query string or as a form",
+            )
+            .expensive(),
+            config,
+        )
+        .reply(Reply::json(202, "The job is queued", "JobCreated"))
+        .reply(too_long)
+        .reply(Reply::json(
+            503,
+            "Too many jobs are queued or kept, see the Retry-After header",
+            "Error",
+        )),
+        Route::new(
+            Method::GET,
+            "/jobs/{id}",
+            Endpoint::JobStatus,
+            "the status of a job: queued, running, done or failed, with its progress",
+        )
+        .param(Param::path("id", "the job id"))
+        .reply(Reply::json(200, "The job", "Job"))
+        .job_errors(),
+        Route::new(
+            Method::GET,
+            "/jobs/{id}/result",
+            Endpoint::JobResult,
+            "the extracted file of a done job",
+        )
+        .param(Param::path("id", "the job id"))
+        .reply(Reply::body(
+            200,
+            "The clip",
+            &[
+                "image/gif",
+                "video/mp4",
+                "video/webm",
+                "image/webp",
+                "image/apng",
+            ],
+        ))
+        .reply(Reply::json(409, "The job is not done", "Error"))
+        .job_errors(),
+        Route::new(
+            Method::GET,
+            "/jobs/{id}/events",
+            Endpoint::JobEvents,
+            "Server-Sent Events of a job: status, progress in percent, then done with the \
+             result url or failed with the error",
+        )
+        .param(Param::path("id", "the job id"))
+        .reply(Reply::body(200, "The events", &["text/event-stream"]))
+        .job_errors(),
+    ]
+}
+
+// The params of /extract, shared with the jobs.
+fn clip_params(route: Route, config: &Config) -> Route {
+    let clips = &config.clips;
+    route
+        .param(Param::query(
+            "url",
+            "the exteracted video url from /watch endpoint",
+        ))
+        .param(Param::query("start", "the start time, like 1:02.5, 62.5, 1m2s or 01:02:03"))
+        .param(Param::query(
+            "end",
+            format!(
+                "the end time in the format of start, less than {} seconds after it",
+                clips.max_seconds
+            ),
+        ))
+        .param(
+            Param::query(
+                "format",
+                format!(
+                    "gif, mp4, webm, webp or apng, defaults to {}",
+                    clips.format.extension()
+                ),
+            )
+            .optional()
+            .one_of(&["gif", "mp4", "webm", "webp", "apng", "png"]),
+        )
+        .param(
+            Param::query(
+                "width",
+                format!(
+                    "the width in pixels, from {} to {}, defaults to {}",
+                    clips.min_width, clips.max_width, clips.width
+                ),
+            )
+            .optional()
+            .integer(),
+        )
+        .param(
+            Param::query(
+                "fps",
+                format!(
+                    "the frame rate, up to {}, defaults to the video one",
+                    clips.max_fps
+                ),
+            )
+            .optional()
+            .integer(),
+        )
+        .param(
+            Param::query(
+                "quality",
+                format!("from 1 to 100, defaults to {}", clips.quality),
+            )
+            .optional()
+            .integer(),
+        )
+        .param(
+            Param::query(
+                "palette",
+                "true to build a palette first for a better looking gif, slower",
+            )
+            .optional()
+            .boolean(),
+        )
+        .param(
+            Param::query(
+                "dither",
+                "with palette, bayer, heckbert, floyd_steinberg, sierra2, sierra2_4a (default) or none",
+            )
+            .optional()
+            .one_of(&[
+                "bayer",
+                "heckbert",
+                "floyd_steinberg",
+                "sierra2",
+                "sierra2_4a",
+                "none",
+            ]),
+        )
+        .param(
+            Param::query(
+                "max_colors",
+                "with palette, from 2 to 256, defaults to 256",
+            )
+            .optional()
+            .integer(),
+        )
+        .param(
+            Param::query(
+                "stats_mode",
+                "with palette, full (default), diff or single",
+            )
+            .optional()
+            .one_of(&["full", "diff", "single"]),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn check(path: &str, pairs: &[(&str, &str)]) -> Result<(), ErrorCode> {
+        let routes = routes(&Config::default());
+        find(&routes, &Method::GET, path)
+            .unwrap()
+            .check_params(&query(pairs))
+            .map_err(|error| error.code)
+    }
+
+    #[test]
+    fn every_endpoint_is_listed_once() {
+        let all = Endpoint::all();
+        assert_eq!(all.len(), 12);
+        for (i, endpoint) in all.iter().enumerate() {
+            assert!(!all[i + 1..].contains(endpoint), "{:?} twice", endpoint);
+        }
+    }
+
+    #[test]
+    fn checks_the_params_of_the_route() {
+        let clip = [("url", "u"), ("start", "1"), ("end", "2")];
+        assert_eq!(check("/extract", &clip), Ok(()));
+        assert_eq!(check("/extract", &clip[1..]), Err(ErrorCode::MissingParam));
+        let with = |key, value| {
+            let mut pairs = clip.to_vec();
+            pairs.push((key, value));
+            check("/extract", &pairs)
+        };
+        assert_eq!(with("width", "320"), Ok(()));
+        assert_eq!(with("width", "wide"), Err(ErrorCode::InvalidParam));
+        assert_eq!(with("fps", "-1"), Err(ErrorCode::InvalidParam));
+        assert_eq!(with("palette", "1"), Ok(()));
+        assert_eq!(with("palette", "yes"), Err(ErrorCode::InvalidParam));
+        assert_eq!(with("format", "WEBM"), Ok(()));
+        assert_eq!(with("format", "avi"), Err(ErrorCode::InvalidParam));
+        // the params the route doesn't know are left alone.
+        assert_eq!(with("color", "red"), Ok(()));
+        assert_eq!(check("/frame", &[("time", "1")]), Ok(()));
+        assert_eq!(check("/audio", &[("v", "id"), ("format", "mp3")]), Ok(()));
+        assert_eq!(
+            check("/audio", &[("v", "id"), ("format", "flac")]),
+            Err(ErrorCode::InvalidParam)
+        );
+        assert_eq!(check("/jobs/abc", &[]), Ok(()));
+    }
+}